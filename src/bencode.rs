@@ -0,0 +1,661 @@
+//! Bencode is the encoding used by the peer-to-peer file sharing system BitTorrent for storing and
+//! transmitting loosely structured data.
+//!
+//! It supports four different types of values:
+//! - byte strings, encoded as `<length>:<contents>`
+//! - integers, encoded as `i<integer>e`
+//! - lists, encoded as `l<values>e`
+//! - dictionaries, encoded as `d<key><value>...e` where every key is a byte string
+//!
+//! The decoder works on raw bytes so that binary strings (e.g. the `pieces` field of a torrent)
+//! survive untouched, and reports malformed input as a [`DecodeError`] carrying the byte offset.
+//!
+//! The encoder always produces the canonical form: dictionary keys sorted as raw byte strings and
+//! integers without leading zeros or `-0`. Hence `decode(&encode(v)) == v` for every value, and
+//! `encode(&decode(b)?) == b` whenever `b` is itself canonical.
+//!
+//! Torrents found in the wild are not always canonical, which matters because the info hash is
+//! computed over the exact bytes. [`decode`] accepts such input, [`validate`] lists every
+//! [`Violation`] it contains and [`decode_strict`] rejects it.
+use anyhow::bail;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+/// Maximum nesting of lists and dictionaries accepted by the decoder, so that hostile input
+/// cannot exhaust the stack.
+const MAX_DEPTH: usize = 512;
+
+/// A decoded bencode value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this value is a dictionary.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict().and_then(|d| d.get(key.as_bytes()))
+    }
+
+    /// Appends the canonical encoding of this value to `out`.
+    pub fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => {
+                out.push(b'i');
+                out.extend_from_slice(n.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Value::Bytes(b) => encode_bytes(b, out),
+            Value::List(l) => {
+                out.push(b'l');
+                for v in l {
+                    v.encode_to(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(d) => {
+                // BTreeMap iterates in byte order, which is exactly the order bencode requires.
+                out.push(b'd');
+                for (k, v) in d {
+                    encode_bytes(k, out);
+                    v.encode_to(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    /// Builds a value from JSON: strings become byte strings, integers stay integers, arrays
+    /// become lists and objects become dictionaries. Floats, booleans and null have no bencode
+    /// counterpart and are rejected.
+    pub fn from_json(json: &serde_json::Value) -> anyhow::Result<Value> {
+        Ok(match json {
+            serde_json::Value::String(s) => Value::Bytes(s.as_bytes().to_vec()),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(n) => Value::Int(n),
+                None => bail!("bencode only supports 64-bit integers, got {}", n),
+            },
+            serde_json::Value::Array(a) => Value::List(
+                a.iter()
+                    .map(Value::from_json)
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            serde_json::Value::Object(o) => Value::Dict(
+                o.iter()
+                    .map(|(k, v)| Ok((k.as_bytes().to_vec(), Value::from_json(v)?)))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            serde_json::Value::Bool(_) | serde_json::Value::Null => {
+                bail!("bencode has no representation for {}", json)
+            }
+        })
+    }
+
+    /// Converts the value to JSON. Byte strings become JSON strings, with any invalid UTF-8
+    /// replaced by U+FFFD.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Int(n) => (*n).into(),
+            Value::Bytes(b) => String::from_utf8_lossy(b).into_owned().into(),
+            Value::List(l) => l.iter().map(Value::to_json).collect::<Vec<_>>().into(),
+            Value::Dict(d) => d
+                .iter()
+                .map(|(k, v)| (String::from_utf8_lossy(k).into_owned(), v.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Bytes(s.into_bytes())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(b: &[u8]) -> Self {
+        Value::Bytes(b.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Bytes(b)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(l: Vec<Value>) -> Self {
+        Value::List(l)
+    }
+}
+
+impl From<BTreeMap<Vec<u8>, Value>> for Value {
+    fn from(d: BTreeMap<Vec<u8>, Value>) -> Self {
+        Value::Dict(d)
+    }
+}
+
+/// Encodes a value in canonical form.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode_to(&mut out);
+    out
+}
+
+fn encode_bytes(b: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(b.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(b);
+}
+
+/// The reason a buffer could not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnexpectedEof,
+    UnexpectedByte(u8),
+    InvalidInteger,
+    IntegerOverflow,
+    InvalidLength,
+    NonStringKey,
+    TooDeep,
+    TrailingData,
+    NonCanonical(ViolationKind),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnexpectedEof => f.write_str("unexpected end of input"),
+            ErrorKind::UnexpectedByte(b) => write!(f, "unexpected byte {:#04x}", b),
+            ErrorKind::InvalidInteger => f.write_str("invalid integer"),
+            ErrorKind::IntegerOverflow => f.write_str("integer out of range"),
+            ErrorKind::InvalidLength => f.write_str("invalid byte string length"),
+            ErrorKind::NonStringKey => f.write_str("dictionary key is not a byte string"),
+            ErrorKind::TooDeep => f.write_str("values nested too deeply"),
+            ErrorKind::TrailingData => f.write_str("trailing data after value"),
+            ErrorKind::NonCanonical(v) => write!(f, "non-canonical encoding: {}", v),
+        }
+    }
+}
+
+/// A way in which otherwise well-formed bencode deviates from the canonical form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// An integer or a string length written with leading zeros, e.g. `i03e` or `03:abc`.
+    LeadingZero,
+    /// The integer `i-0e`.
+    NegativeZero,
+    /// A dictionary key that sorts before the key preceding it.
+    UnsortedKey,
+    /// A dictionary key that appears more than once.
+    DuplicateKey,
+}
+
+impl fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ViolationKind::LeadingZero => f.write_str("number with leading zeros"),
+            ViolationKind::NegativeZero => f.write_str("negative zero"),
+            ViolationKind::UnsortedKey => f.write_str("dictionary keys out of order"),
+            ViolationKind::DuplicateKey => f.write_str("duplicate dictionary key"),
+        }
+    }
+}
+
+/// A canonical-form violation found at `offset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub offset: usize,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.offset)
+    }
+}
+
+/// Error returned when decoding fails, with the offset of the offending byte.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{kind} at byte {offset}")]
+pub struct DecodeError {
+    pub offset: usize,
+    pub kind: ErrorKind,
+}
+
+/// Decodes a buffer that must contain exactly one bencoded value.
+pub fn decode(buf: &[u8]) -> Result<Value, DecodeError> {
+    let mut decoder = Decoder::new(buf);
+    let value = decoder.decode_value()?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// Decodes a buffer like [`decode`], but fails on the first canonical-form violation.
+pub fn decode_strict(buf: &[u8]) -> Result<Value, DecodeError> {
+    let mut decoder = Decoder::new(buf).strict();
+    let value = decoder.decode_value()?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// Checks that `buf` is well formed and returns every canonical-form violation in it, in the
+/// order they appear. An empty list means the buffer re-encodes to exactly the same bytes.
+pub fn validate(buf: &[u8]) -> Result<Vec<Violation>, DecodeError> {
+    let mut decoder = Decoder::new(buf);
+    decoder.decode_value()?;
+    decoder.finish()?;
+    Ok(decoder.violations)
+}
+
+/// Returns the byte range that the value stored under `key` occupies in the top-level dictionary
+/// of `buf`, so the original encoding can be used verbatim (e.g. to compute an info hash).
+///
+/// If the key is repeated, the last occurrence wins, matching [`decode`].
+pub fn find_value_span(buf: &[u8], key: &[u8]) -> Result<Option<Range<usize>>, DecodeError> {
+    let mut decoder = Decoder::new(buf);
+    match decoder.peek()? {
+        b'd' => decoder.pos += 1,
+        b => return Err(decoder.error(ErrorKind::UnexpectedByte(b))),
+    }
+    let mut span = None;
+    while decoder.peek()? != b'e' {
+        if !decoder.peek()?.is_ascii_digit() {
+            return Err(decoder.error(ErrorKind::NonStringKey));
+        }
+        let k = decoder.bytes()?;
+        let start = decoder.pos;
+        decoder.decode_value()?;
+        if k == key {
+            span = Some(start..decoder.pos);
+        }
+    }
+    decoder.pos += 1;
+    decoder.finish()?;
+    Ok(span)
+}
+
+/// Streaming decoder over a byte buffer.
+///
+/// Besides [`decode`], this allows decoding several values back to back and finding the byte
+/// span each value occupies through [`Decoder::position`].
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    depth: usize,
+    strict: bool,
+    violations: Vec<Violation>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            depth: 0,
+            strict: false,
+            violations: Vec::new(),
+        }
+    }
+
+    /// Turns canonical-form violations into [`ErrorKind::NonCanonical`] errors.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Canonical-form violations seen so far.
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Offset of the next byte to be decoded.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns the bytes that have not been decoded yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Fails with [`ErrorKind::TrailingData`] unless the whole buffer has been consumed.
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        if self.pos < self.buf.len() {
+            return Err(self.error(ErrorKind::TrailingData));
+        }
+        Ok(())
+    }
+
+    pub fn decode_value(&mut self) -> Result<Value, DecodeError> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let n = self.integer(b'e')?;
+                Ok(Value::Int(n))
+            }
+            b'l' => {
+                self.pos += 1;
+                self.enter()?;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.decode_value()?);
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                self.enter()?;
+                let mut dict = BTreeMap::new();
+                let mut previous: Option<Vec<u8>> = None;
+                while self.peek()? != b'e' {
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(self.error(ErrorKind::NonStringKey));
+                    }
+                    let key_start = self.pos;
+                    let key = self.bytes()?;
+                    if dict.contains_key(&key) {
+                        self.violation(key_start, ViolationKind::DuplicateKey)?;
+                    } else if previous.as_ref().is_some_and(|p| key < *p) {
+                        self.violation(key_start, ViolationKind::UnsortedKey)?;
+                    }
+                    let value = self.decode_value()?;
+                    previous = Some(key.clone());
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => self.bytes().map(Value::Bytes),
+            b => Err(self.error(ErrorKind::UnexpectedByte(b))),
+        }
+    }
+
+    fn error(&self, kind: ErrorKind) -> DecodeError {
+        DecodeError {
+            offset: self.pos,
+            kind,
+        }
+    }
+
+    fn violation(&mut self, offset: usize, kind: ViolationKind) -> Result<(), DecodeError> {
+        if self.strict {
+            return Err(DecodeError {
+                offset,
+                kind: ErrorKind::NonCanonical(kind),
+            });
+        }
+        self.violations.push(Violation { offset, kind });
+        Ok(())
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error(ErrorKind::UnexpectedEof))
+    }
+
+    fn enter(&mut self) -> Result<(), DecodeError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(ErrorKind::TooDeep));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Parses a decimal integer terminated by `end`, consuming the terminator.
+    fn integer(&mut self, end: u8) -> Result<i64, DecodeError> {
+        let start = self.pos;
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let digits_start = self.pos;
+        let mut n: i64 = 0;
+        loop {
+            let b = self.peek()?;
+            if b == end {
+                break;
+            }
+            if !b.is_ascii_digit() {
+                return Err(self.error(ErrorKind::UnexpectedByte(b)));
+            }
+            let digit = (b - b'0') as i64;
+            n = n
+                .checked_mul(10)
+                .and_then(|n| {
+                    if negative {
+                        n.checked_sub(digit)
+                    } else {
+                        n.checked_add(digit)
+                    }
+                })
+                .ok_or(DecodeError {
+                    offset: start,
+                    kind: ErrorKind::IntegerOverflow,
+                })?;
+            self.pos += 1;
+        }
+        if self.pos == digits_start {
+            return Err(DecodeError {
+                offset: start,
+                kind: ErrorKind::InvalidInteger,
+            });
+        }
+        if negative && n == 0 {
+            self.violation(start, ViolationKind::NegativeZero)?;
+        } else if self.buf[digits_start] == b'0' && self.pos - digits_start > 1 {
+            self.violation(start, ViolationKind::LeadingZero)?;
+        }
+        self.pos += 1;
+        Ok(n)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let start = self.pos;
+        if self.peek()? == b'-' {
+            return Err(self.error(ErrorKind::InvalidLength));
+        }
+        let len = self.integer(b':')?;
+        let len = usize::try_from(len).map_err(|_| DecodeError {
+            offset: start,
+            kind: ErrorKind::InvalidLength,
+        })?;
+        if self.buf.len() - self.pos < len {
+            return Err(DecodeError {
+                offset: self.buf.len(),
+                kind: ErrorKind::UnexpectedEof,
+            });
+        }
+        let bytes = self.buf[self.pos..self.pos + len].to_vec();
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(offset: usize, kind: ErrorKind) -> DecodeError {
+        DecodeError { offset, kind }
+    }
+
+    fn non_canonical(offset: usize, kind: ViolationKind) -> DecodeError {
+        error(offset, ErrorKind::NonCanonical(kind))
+    }
+
+    #[test]
+    fn decode_inverts_encode() {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"int".to_vec(), Value::from(-42));
+        dict.insert(b"bytes".to_vec(), Value::from(&[0u8, 0xff, b':'][..]));
+        dict.insert(b"empty".to_vec(), Value::from(""));
+        dict.insert(
+            b"list".to_vec(),
+            Value::from(vec![Value::from(0), Value::from("a"), Value::List(vec![])]),
+        );
+        dict.insert(b"dict".to_vec(), Value::from(BTreeMap::new()));
+        let value = Value::from(dict);
+        assert_eq!(decode(&encode(&value)), Ok(value));
+    }
+
+    #[test]
+    fn encode_inverts_decode_of_canonical_input() {
+        let inputs: [&[u8]; 7] = [
+            b"i0e",
+            b"i-9223372036854775808e",
+            b"0:",
+            b"4:spam",
+            b"le",
+            b"l4:spami42ee",
+            b"d3:bar4:spam3:fooi42e4:listli1eld1:a0:eeee",
+        ];
+        for input in inputs {
+            assert_eq!(encode(&decode(input).unwrap()), input);
+        }
+    }
+
+    #[test]
+    fn reports_where_malformed_input_fails() {
+        let cases: [(&[u8], DecodeError); 9] = [
+            (b"", error(0, ErrorKind::UnexpectedEof)),
+            (b"x", error(0, ErrorKind::UnexpectedByte(b'x'))),
+            (b"ie", error(1, ErrorKind::InvalidInteger)),
+            (b"i1-e", error(2, ErrorKind::UnexpectedByte(b'-'))),
+            (b"i12", error(3, ErrorKind::UnexpectedEof)),
+            (
+                b"i9223372036854775808e",
+                error(1, ErrorKind::IntegerOverflow),
+            ),
+            (b"5:abc", error(5, ErrorKind::UnexpectedEof)),
+            (b"di1ei2ee", error(1, ErrorKind::NonStringKey)),
+            (b"i1ei2e", error(3, ErrorKind::TrailingData)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(decode(input), Err(expected), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn strict_decoding_rejects_non_canonical_input() {
+        let cases: [(&[u8], DecodeError); 5] = [
+            (b"i-0e", non_canonical(1, ViolationKind::NegativeZero)),
+            (b"i03e", non_canonical(1, ViolationKind::LeadingZero)),
+            (b"03:abc", non_canonical(0, ViolationKind::LeadingZero)),
+            (
+                b"d1:bi1e1:ai2ee",
+                non_canonical(7, ViolationKind::UnsortedKey),
+            ),
+            (
+                b"d1:ai1e1:ai2ee",
+                non_canonical(7, ViolationKind::DuplicateKey),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(decode_strict(input), Err(expected), "{:?}", input);
+            assert!(decode(input).is_ok(), "{:?}", input);
+        }
+        // A repeated key keeps its last value.
+        let value = decode(b"d1:ai1e1:ai2ee").unwrap();
+        assert_eq!(value.get("a"), Some(&Value::Int(2)));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            decode(&nested(MAX_DEPTH + 1)),
+            Err(error(MAX_DEPTH + 1, ErrorKind::TooDeep))
+        );
+    }
+
+    #[test]
+    fn validate_lists_every_violation() {
+        assert_eq!(validate(b"d3:bar4:spam3:fooi42ee"), Ok(Vec::new()));
+        assert_eq!(
+            validate(b"d1:bi03e1:ai-0ee"),
+            Ok(vec![
+                Violation {
+                    offset: 5,
+                    kind: ViolationKind::LeadingZero
+                },
+                Violation {
+                    offset: 8,
+                    kind: ViolationKind::UnsortedKey
+                },
+                Violation {
+                    offset: 12,
+                    kind: ViolationKind::NegativeZero
+                },
+            ])
+        );
+        assert_eq!(
+            validate(b"d1:ai1e"),
+            Err(error(7, ErrorKind::UnexpectedEof))
+        );
+    }
+
+    #[test]
+    fn finds_the_raw_span_of_a_value() {
+        let buf = b"d4:infod3:fooi-0ee4:name1:xe";
+        let span = find_value_span(buf, b"info").unwrap().unwrap();
+        assert_eq!(&buf[span], b"d3:fooi-0ee");
+        assert_eq!(find_value_span(buf, b"missing"), Ok(None));
+    }
+}
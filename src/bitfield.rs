@@ -0,0 +1,234 @@
+//! Sets of pieces in the wire format of the `bitfield` message.
+use crate::{
+    error::{Error, Result},
+    peer::{Message, MessageTag},
+};
+
+/// One bit per piece, the high bit of the first byte being piece 0. The spare bits at the end
+/// of the last byte are always clear.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// A bitfield of `len` pieces, none of which are set.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Reads a bitfield of `len` pieces, such as the payload of a `bitfield` message. Fails if
+    /// the number of bytes does not fit `len` or if any of the spare bits is set.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(Error::Protocol(format!(
+                "bitfield of {} bytes for {} pieces",
+                bytes.len(),
+                len
+            )));
+        }
+        let spare_mask = (1u16 << (bytes.len() * 8 - len)) - 1;
+        if bytes
+            .last()
+            .is_some_and(|&last| last as u16 & spare_mask != 0)
+        {
+            return Err(Error::Protocol("bitfield has spare bits set".to_string()));
+        }
+        Ok(Self {
+            bytes: bytes.to_vec(),
+            len,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The number of pieces, set or not.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether piece `index` is set; pieces past the end never are.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(
+            index < self.len,
+            "piece {} out of range for a bitfield of {} pieces",
+            index,
+            self.len
+        );
+        if value {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        } else {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    /// The number of pieces that are set.
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// Whether any piece is set.
+    pub fn any(&self) -> bool {
+        self.bytes.iter().any(|&b| b != 0)
+    }
+
+    /// Whether every piece is set.
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Whether each piece is set, in piece order.
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(|index| self.get(index))
+    }
+
+    /// Indexes of the pieces that are set.
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| self.get(index))
+    }
+
+    /// The pieces set here but not in `other`. For a peer's bitfield and ours, these are the
+    /// pieces we want from the peer.
+    pub fn difference(&self, other: &Bitfield) -> Bitfield {
+        let bytes = self
+            .bytes
+            .iter()
+            .zip(other.bytes.iter().chain(std::iter::repeat(&0)))
+            .map(|(a, b)| a & !b)
+            .collect();
+        Self {
+            bytes,
+            len: self.len,
+        }
+    }
+
+    /// A `bitfield` message telling a peer which pieces we have.
+    pub fn to_message(&self) -> Message {
+        Message {
+            tag: MessageTag::Bitfield,
+            payload: self.bytes.clone(),
+        }
+    }
+}
+
+impl FromIterator<bool> for Bitfield {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        let mut bitfield = Self::new(0);
+        for value in iter {
+            if bitfield.len.is_multiple_of(8) {
+                bitfield.bytes.push(0);
+            }
+            bitfield.len += 1;
+            bitfield.set(bitfield.len - 1, value);
+        }
+        bitfield
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_pieces_from_the_high_bit() {
+        let bitfield = Bitfield::from_bytes(&[0b1000_0001, 0b0100_0000], 10).unwrap();
+        assert_eq!(bitfield.ones().collect::<Vec<_>>(), [0, 7, 9]);
+        assert!(bitfield.get(0) && !bitfield.get(1) && bitfield.get(7));
+        // Pieces past the end are never set.
+        assert!(!bitfield.get(10) && !bitfield.get(100));
+    }
+
+    #[test]
+    fn rejects_wrong_lengths_and_spare_bits() {
+        for (bytes, len) in [(&[0u8][..], 9), (&[0, 0][..], 8), (&[][..], 1)] {
+            assert!(
+                matches!(Bitfield::from_bytes(bytes, len), Err(Error::Protocol(_))),
+                "{:?} for {} pieces",
+                bytes,
+                len
+            );
+        }
+        assert!(matches!(
+            Bitfield::from_bytes(&[0, 0b0010_0000], 10),
+            Err(Error::Protocol(_))
+        ));
+        assert!(Bitfield::from_bytes(&[0xff, 0b1100_0000], 10).is_ok());
+        assert!(Bitfield::from_bytes(&[0xff], 8).is_ok());
+        assert!(Bitfield::from_bytes(&[], 0).is_ok());
+    }
+
+    #[test]
+    fn sets_gets_and_counts() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), [0, 0]);
+        assert!(!bitfield.any());
+        bitfield.set(1, true);
+        bitfield.set(9, true);
+        assert_eq!(bitfield.as_bytes(), [0b0100_0000, 0b0100_0000]);
+        assert_eq!(bitfield.count(), 2);
+        assert!(bitfield.any() && !bitfield.is_complete());
+        bitfield.set(1, false);
+        assert_eq!(bitfield.count(), 1);
+        assert_eq!(
+            bitfield.iter().collect::<Vec<_>>(),
+            [false, false, false, false, false, false, false, false, false, true]
+        );
+        for piece in 0..10 {
+            bitfield.set(piece, true);
+        }
+        assert!(bitfield.is_complete());
+        assert_eq!(bitfield.as_bytes(), [0xff, 0b1100_0000]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn set_past_the_end_panics() {
+        Bitfield::new(10).set(10, true);
+    }
+
+    #[test]
+    fn difference_keeps_what_only_the_first_has() {
+        let theirs: Bitfield = [true, true, false, true, true].into_iter().collect();
+        let ours: Bitfield = [true, false, false, false, true].into_iter().collect();
+        let wanted = theirs.difference(&ours);
+        assert_eq!(wanted.len(), 5);
+        assert_eq!(wanted.ones().collect::<Vec<_>>(), [1, 3]);
+        assert!(!ours.difference(&theirs).any());
+    }
+
+    #[test]
+    fn collects_from_bools() {
+        let bitfield: Bitfield = (0..9).map(|i| i % 4 == 0).collect();
+        assert_eq!(bitfield.len(), 9);
+        assert_eq!(bitfield.as_bytes(), [0b1000_1000, 0b1000_0000]);
+        assert_eq!(
+            Bitfield::from_bytes(bitfield.as_bytes(), 9).unwrap(),
+            bitfield
+        );
+        let empty: Bitfield = std::iter::empty().collect();
+        assert!(empty.is_empty());
+        assert_eq!(empty.as_bytes(), [0u8; 0]);
+    }
+
+    #[test]
+    fn makes_a_bitfield_message() {
+        let bitfield: Bitfield = [true, false, true].into_iter().collect();
+        let message = bitfield.to_message();
+        assert_eq!(message.tag, MessageTag::Bitfield);
+        assert_eq!(message.payload, [0b1010_0000]);
+    }
+}
@@ -0,0 +1,246 @@
+//! Creating metainfo files from data on disk.
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bencode::{self, Value},
+    storage::{FsStorage, Storage},
+    torrent::{hashes::Hashes, File, Info, Keys, Torrent, BLOCK_MAX},
+    verify,
+};
+
+/// Roughly how many pieces an automatically sized torrent should have.
+const TARGET_PIECES: usize = 1500;
+const MAX_PIECE_LENGTH: usize = 1 << 24;
+
+/// Builds a [`Torrent`] for a file or a directory, see [`Torrent::builder`].
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<usize>,
+    trackers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+}
+
+impl TorrentBuilder {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        Self {
+            path,
+            piece_length: None,
+            trackers: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: Some(now),
+            private: false,
+        }
+    }
+
+    /// Uses pieces of `piece_length` bytes, which must be a power of two of at least 16 KiB.
+    /// By default a piece length giving around 1500 pieces is picked.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tier of trackers. The first tracker of the first tier becomes `announce`, and
+    /// `announce-list` is written as soon as there is more than one tracker.
+    pub fn tracker_tier(mut self, tier: Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.trackers.push(tier);
+        }
+        self
+    }
+
+    /// Adds a tier holding only `url`.
+    pub fn announce(self, url: impl Into<String>) -> Self {
+        self.tracker_tier(vec![url.into()])
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = Some(created_by.into());
+        self
+    }
+
+    /// Sets the creation date in seconds since the Unix epoch, or leaves it out for `None`.
+    /// Defaults to the time the builder was created.
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    /// Marks the torrent private, asking clients to only get peers from its trackers.
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Walks the files, hashes their pieces and assembles the torrent.
+    pub fn build(self) -> anyhow::Result<Torrent> {
+        let path = self
+            .path
+            .canonicalize()
+            .with_context(|| format!("resolve {}", self.path.display()))?;
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .context("path has no UTF-8 file name")?
+            .to_string();
+
+        let metadata = std::fs::metadata(&path)?;
+        let (keys, output) = if metadata.is_dir() {
+            let mut files = Vec::new();
+            walk(&path, &mut Vec::new(), &mut files)?;
+            anyhow::ensure!(!files.is_empty(), "{} contains no files", path.display());
+            let parent = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+            (Keys::MultiFile { files }, parent)
+        } else {
+            let length = metadata.len() as usize;
+            (Keys::SingleFile { length }, path.clone())
+        };
+
+        let mut info = Info {
+            name,
+            piece_length: 0,
+            pieces: Hashes(Vec::new()),
+            keys,
+            private: self.private.then_some(1),
+        };
+        let length = info.length();
+        info.piece_length = match self.piece_length {
+            Some(piece_length) => {
+                anyhow::ensure!(
+                    piece_length.is_power_of_two() && piece_length >= BLOCK_MAX as usize,
+                    "piece length must be a power of two of at least {}",
+                    BLOCK_MAX
+                );
+                piece_length
+            }
+            None => auto_piece_length(length),
+        };
+
+        let storage = FsStorage::new(&info, &output)?;
+        let num_pieces = length.div_ceil(info.piece_length);
+        let hashes = verify::map_pieces(num_pieces, |piece_index| {
+            let data = storage.read_block(piece_index, 0, info.piece_len(piece_index))?;
+            Ok::<[u8; 20], std::io::Error>(Sha1::digest(data).into())
+        });
+        info.pieces = Hashes(
+            hashes
+                .into_iter()
+                .collect::<Result<_, _>>()
+                .context("read data to hash")?,
+        );
+
+        let raw_info = bencode::encode(&info_value(&info));
+        let announce = self
+            .trackers
+            .first()
+            .map(|tier| tier[0].clone())
+            .unwrap_or_default();
+        let announce_list =
+            (self.trackers.iter().map(Vec::len).sum::<usize>() > 1).then_some(self.trackers);
+        Ok(Torrent {
+            announce,
+            announce_list,
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date,
+            info,
+            raw_info,
+        })
+    }
+}
+
+/// Collects the regular files below `dir` in a stable (sorted) order, with their paths relative
+/// to the directory the walk started in. Symlinks are skipped rather than followed, so that a
+/// link back up the tree cannot send the walk in circles.
+fn walk(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<File>) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("read directory {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let file_name = entry
+            .file_name()
+            .into_string()
+            .map_err(|n| anyhow::anyhow!("file name {:?} is not UTF-8", n))?;
+        let metadata = std::fs::symlink_metadata(entry.path())?;
+        if metadata.is_symlink() {
+            eprintln!("skipping symlink {}", entry.path().display());
+            continue;
+        }
+        prefix.push(file_name);
+        if metadata.is_dir() {
+            walk(&entry.path(), prefix, files)?;
+        } else if metadata.is_file() {
+            files.push(File {
+                length: metadata.len() as usize,
+                path: prefix.clone(),
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Picks the smallest power of two of at least 16 KiB that keeps the number of pieces around
+/// [`TARGET_PIECES`].
+fn auto_piece_length(length: usize) -> usize {
+    let mut piece_length = BLOCK_MAX as usize;
+    while length / piece_length > TARGET_PIECES && piece_length < MAX_PIECE_LENGTH {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+fn info_value(info: &Info) -> Value {
+    let mut dict = BTreeMap::from([
+        (b"name".to_vec(), Value::from(info.name.as_str())),
+        (
+            b"piece length".to_vec(),
+            Value::Int(info.piece_length as i64),
+        ),
+        (b"pieces".to_vec(), Value::Bytes(info.pieces.0.concat())),
+    ]);
+    match &info.keys {
+        Keys::SingleFile { length } => {
+            dict.insert(b"length".to_vec(), Value::Int(*length as i64));
+        }
+        Keys::MultiFile { files } => {
+            let files = files
+                .iter()
+                .map(|f| {
+                    Value::Dict(BTreeMap::from([
+                        (b"length".to_vec(), Value::Int(f.length as i64)),
+                        (
+                            b"path".to_vec(),
+                            Value::List(f.path.iter().map(|p| Value::from(p.as_str())).collect()),
+                        ),
+                    ]))
+                })
+                .collect();
+            dict.insert(b"files".to_vec(), Value::List(files));
+        }
+    }
+    if let Some(private) = info.private {
+        dict.insert(b"private".to_vec(), Value::Int(private as i64));
+    }
+    Value::Dict(dict)
+}
@@ -0,0 +1,1014 @@
+//! Downloading a torrent from many peers at once.
+//!
+//! Every peer connection runs in a task of its own. While a peer has unchoked us, it keeps a
+//! window of block requests outstanding (see [`RequestWindow`]), claiming pieces that the peer
+//! has and nobody else is downloading as it needs more blocks to ask for; the shared
+//! [`PiecePicker`] decides which. A piece claimed by a peer that chokes us or goes away is
+//! released again, together with the blocks received so far, so another peer can finish it.
+//!
+//! Once every missing piece is being downloaded, the download is in its endgame: peers with
+//! nothing left to claim ask for blocks that were already requested from others, and whoever
+//! delivers a block first wins, the other peers get a `cancel` for it. This keeps the last
+//! few blocks from waiting on a slow peer.
+//!
+//! The coordinator in [`download`] keeps enough peers connected, saves the resume state after
+//! every piece and keeps the trackers informed.
+use anyhow::Context;
+use futures_util::SinkExt;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, watch},
+    task::JoinSet,
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    bitfield::Bitfield,
+    error::{Error, Result},
+    extension::Extensions,
+    peer::{self, Handshake, Message, MessageFramer, MessageTag, PeerId, PieceResponse, Request},
+    picker::PiecePicker,
+    pipeline::RequestWindow,
+    resume::ResumeData,
+    storage::{FsStorage, Storage},
+    torrent::{Info, Torrent, BLOCK_MAX},
+    tracker::TrackerSession,
+};
+
+/// How many peers we download from at the same time.
+pub const MAX_PEERS: usize = 30;
+
+/// How long a peer may take to accept the connection and answer the handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peers send a keep-alive every two minutes; a peer quiet for longer is gone.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// How long a peer may leave our requests unanswered before we give its pieces to others.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many announces in a row may bring no new peers while we have none before the
+/// download is given up.
+const MAX_EMPTY_ANNOUNCES: usize = 3;
+
+/// What the peer tasks share: the torrent, where its data goes and which pieces are taken.
+struct Shared {
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+    info: Info,
+    storage: FsStorage,
+    // Cap on the outstanding requests per peer.
+    max_requests: usize,
+    state: Mutex<State>,
+    // Bumped whenever a piece is released or completed, to wake peers waiting for work.
+    changed: watch::Sender<()>,
+    // Tells the coordinator about every verified piece.
+    done: mpsc::UnboundedSender<usize>,
+}
+
+struct State {
+    picker: PiecePicker,
+    // Pieces some blocks of which were requested or received. A piece released by the peer
+    // that claimed it stays here with the blocks received so far, so that whoever picks it up
+    // next only has to download the rest.
+    downloads: BTreeMap<usize, PieceDownload>,
+    // Pieces that failed verification with blocks from more than one peer, with the index,
+    // sender and hash of each of those blocks. Such a piece is downloaded from a single peer
+    // next, and once it verifies, whoever sent a block that differs is banned.
+    failed: BTreeMap<usize, Vec<(usize, SocketAddr, [u8; 20])>>,
+    // Peers found to have sent bad blocks, with a piece they corrupted.
+    banned: BTreeMap<SocketAddr, usize>,
+}
+
+impl State {
+    /// Picks the next block for the peer at `addr` to request, given the pieces it has and the
+    /// blocks it asked for already. The blocks of the pieces the peer claimed come first, then
+    /// those of a newly claimed piece. In the endgame, it gets a block that is already
+    /// requested from others, one asked from as few peers as possible, unless the piece failed
+    /// verification before and has to come from a single peer.
+    fn next_block(
+        &mut self,
+        info: &Info,
+        addr: SocketAddr,
+        remote: &Bitfield,
+        requested: &BTreeSet<(usize, usize)>,
+    ) -> Option<(usize, usize)> {
+        loop {
+            let wanted = self
+                .downloads
+                .values_mut()
+                .filter(|d| d.owner == Some(addr))
+                .find_map(|d| {
+                    let block = d.blocks.iter().position(|&b| b == Block::Pending(0))?;
+                    d.blocks[block] = Block::Pending(1);
+                    Some((d.piece, block))
+                });
+            if wanted.is_some() {
+                return wanted;
+            }
+            let Some(piece) = self.picker.pick(remote) else {
+                break;
+            };
+            self.downloads
+                .entry(piece)
+                .or_insert_with(|| PieceDownload::new(info, piece))
+                .owner = Some(addr);
+        }
+
+        if !self.picker.in_endgame() {
+            return None;
+        }
+        let (piece, block, _) = self
+            .downloads
+            .values()
+            .filter(|d| remote.get(d.piece) && !self.failed.contains_key(&d.piece))
+            .flat_map(|d| {
+                d.blocks
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(block, &b)| match b {
+                        Block::Pending(n) if !requested.contains(&(d.piece, block)) => {
+                            Some((d.piece, block, n))
+                        }
+                        _ => None,
+                    })
+            })
+            .min_by_key(|&(_, _, n)| n)?;
+        let download = self.downloads.get_mut(&piece).expect("found above");
+        if let Block::Pending(n) = &mut download.blocks[block] {
+            *n += 1;
+        }
+        Some((piece, block))
+    }
+
+    /// Whether a block is still worth receiving.
+    fn is_wanted(&self, piece: usize, block: usize) -> bool {
+        self.downloads
+            .get(&piece)
+            .is_some_and(|d| matches!(d.blocks[block], Block::Pending(_)))
+    }
+
+    /// Forgets that a block was requested from one peer.
+    fn unrequest(&mut self, piece: usize, block: usize) {
+        if let Some(download) = self.downloads.get_mut(&piece) {
+            if let Block::Pending(n) = &mut download.blocks[block] {
+                *n = n.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Gives up the requests of the peer at `addr` and the pieces it claimed, so that other
+    /// peers can download the rest of them.
+    fn release(&mut self, addr: SocketAddr, requested: &BTreeSet<(usize, usize)>) {
+        for &(piece, block) in requested {
+            self.unrequest(piece, block);
+        }
+        let picker = &mut self.picker;
+        let failed = &self.failed;
+        self.downloads.retain(|&piece, download| {
+            if download.owner != Some(addr) {
+                return true;
+            }
+            // A piece that failed before has to come from a single peer.
+            if failed.contains_key(&piece) {
+                picker.release(piece, false);
+                return false;
+            }
+            download.owner = None;
+            picker.release(piece, download.received > 0);
+            // Keep what was received, and what other peers still send us in the endgame.
+            download.received > 0 || download.blocks.iter().any(|&b| b != Block::Pending(0))
+        });
+    }
+}
+
+impl Shared {
+    fn is_complete(&self) -> bool {
+        self.state.lock().unwrap().picker.is_complete()
+    }
+
+    /// Whether `remote` has any piece we still need.
+    fn wants_any(&self, remote: &Bitfield) -> bool {
+        self.state.lock().unwrap().picker.wants_any(remote)
+    }
+
+    fn release(&self, addr: SocketAddr, requested: &BTreeSet<(usize, usize)>) {
+        self.state.lock().unwrap().release(addr, requested);
+        self.changed.send_replace(());
+    }
+
+    /// The piece the peer at `addr` was found to have corrupted, if any.
+    fn banned(&self, addr: SocketAddr) -> Option<usize> {
+        self.state.lock().unwrap().banned.get(&addr).copied()
+    }
+
+    /// Stores a block received from the peer at `addr`. Returns the piece once all of its
+    /// blocks are there, which is `None` for a block that somebody else delivered first.
+    fn receive(
+        &self,
+        addr: SocketAddr,
+        piece: usize,
+        block: usize,
+        data: &[u8],
+    ) -> Result<Option<PieceDownload>> {
+        let mut state = self.state.lock().unwrap();
+        let Some(download) = state.downloads.get_mut(&piece) else {
+            return Ok(None);
+        };
+        let Block::Pending(requested) = download.blocks[block] else {
+            return Ok(None);
+        };
+        let expected = download.block_len(block);
+        if data.len() != expected {
+            return Err(Error::Protocol(format!(
+                "asked for {} bytes at {} of piece {}, got {}",
+                expected,
+                block * BLOCK_MAX as usize,
+                piece,
+                data.len()
+            )));
+        }
+        let begin = block * BLOCK_MAX as usize;
+        download.buf[begin..begin + expected].copy_from_slice(data);
+        download.blocks[block] = Block::Received(addr);
+        download.received += expected;
+
+        let complete = download.received == download.buf.len();
+        let download = complete.then(|| state.downloads.remove(&piece).expect("found above"));
+        drop(state);
+        if requested > 1 {
+            // The other peers asked for this block too; they should cancel it.
+            self.changed.send_replace(());
+        }
+        Ok(download)
+    }
+
+    /// Checks a downloaded piece against its hash and completes it. A piece that does not
+    /// match is thrown away. Only if a single peer sent all of it is that peer to blame, so
+    /// that is the only case that returns [`Error::HashMismatch`].
+    fn verify(&self, download: PieceDownload) -> Result<()> {
+        let piece = download.piece;
+        let hash: [u8; 20] = Sha1::digest(&download.buf).into();
+        if hash == self.info.pieces.0[piece] {
+            return self.complete(piece, &download.buf);
+        }
+
+        let blocks: Vec<(usize, SocketAddr, [u8; 20])> = (0..download.blocks.len())
+            .map(|block| {
+                let Block::Received(addr) = download.blocks[block] else {
+                    unreachable!("all blocks of a complete piece are received")
+                };
+                (block, addr, download.block_hash(block))
+            })
+            .collect();
+        let single_source = blocks.iter().all(|&(_, addr, _)| addr == blocks[0].1);
+        {
+            let mut state = self.state.lock().unwrap();
+            state.picker.release(piece, false);
+            if !single_source {
+                state.failed.entry(piece).or_default().extend(blocks);
+            }
+        }
+        self.changed.send_replace(());
+        if single_source {
+            return Err(Error::HashMismatch(piece));
+        }
+        eprintln!(
+            "piece {} does not match its hash, downloading it from a single peer",
+            piece
+        );
+        Ok(())
+    }
+
+    /// Gives up a piece that could not be stored.
+    fn discard(&self, piece: usize) {
+        self.state.lock().unwrap().picker.release(piece, false);
+        self.changed.send_replace(());
+    }
+
+    /// Writes a verified piece to storage and marks it as had. If it failed before, the peers
+    /// that sent blocks that differ from the verified ones are banned.
+    fn complete(&self, piece: usize, data: &[u8]) -> Result<()> {
+        if let Err(e) = self.storage.write_block(piece, 0, data) {
+            self.discard(piece);
+            return Err(e.into());
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            state.picker.complete(piece);
+            for (block, addr, hash) in state.failed.remove(&piece).unwrap_or_default() {
+                let begin = block * BLOCK_MAX as usize;
+                let end = (begin + BLOCK_MAX as usize).min(data.len());
+                if <[u8; 20]>::from(Sha1::digest(&data[begin..end])) != hash {
+                    state.banned.insert(addr, piece);
+                }
+            }
+        }
+        // The coordinator only goes away once the download is over.
+        let _ = self.done.send(piece);
+        self.changed.send_replace(());
+        Ok(())
+    }
+}
+
+/// Downloads every piece of `torrent` that is not on disk yet from the swarm. A single-file
+/// torrent is written to `output`, a multi-file torrent gets its directory tree created at
+/// `output/<name>`.
+///
+/// Every peer gets up to `max_requests` requests outstanding, fewer if it asks for that.
+/// `session` is started once the pieces on disk are known, so that `started` reports what is
+/// really left, then kept up to date with the progress, re-announced whenever its interval is
+/// up and told when the download completes.
+pub async fn download(
+    torrent: &Torrent,
+    output: &Path,
+    peer_id: PeerId,
+    session: &mut TrackerSession,
+    max_requests: usize,
+) -> anyhow::Result<()> {
+    let storage = FsStorage::new(&torrent.info, output)?;
+    storage.create_files().context("create output files")?;
+
+    let info_hash = torrent.info_hash();
+    let resume_path = ResumeData::path(&torrent.info, output);
+    let have = torrent.resume_pieces(&storage, &resume_path)?;
+    let mut left: usize = (0..have.len())
+        .filter(|&i| !have.get(i))
+        .map(|i| torrent.info.piece_len(i))
+        .sum();
+    session.set_left(left);
+    if left == 0 {
+        // Already complete when we started, so there is nothing to announce.
+        return Ok(());
+    }
+    let peers = session.start().await.context("get peers")?;
+
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared {
+        info_hash,
+        peer_id,
+        info: torrent.info.clone(),
+        storage,
+        max_requests,
+        state: Mutex::new(State {
+            picker: PiecePicker::new(have),
+            downloads: BTreeMap::new(),
+            failed: BTreeMap::new(),
+            banned: BTreeMap::new(),
+        }),
+        changed: watch::channel(()).0,
+        done: done_tx,
+    });
+
+    let mut swarm = Swarm::default();
+    swarm.add(peers);
+    let mut tasks = JoinSet::new();
+    // Announces in a row that left us without peers.
+    let mut empty_announces = 0;
+    loop {
+        while tasks.len() < MAX_PEERS {
+            let Some(addr) = swarm.next() else { break };
+            let shared = shared.clone();
+            tasks.spawn(async move { (addr, run_peer(shared, addr).await) });
+        }
+        if tasks.is_empty() && session.can_request_peers() {
+            match session.request_peers().await {
+                Ok(peers) => {
+                    if swarm.add(peers) {
+                        empty_announces = 0;
+                        continue;
+                    }
+                    eprintln!("no new peers");
+                }
+                Err(e) => eprintln!("announce failed: {:#}", e),
+            }
+            empty_announces += 1;
+            anyhow::ensure!(
+                empty_announces < MAX_EMPTY_ANNOUNCES,
+                "no peers left to download from"
+            );
+        }
+
+        // Without peers, ask for more as soon as the tracker allows.
+        let mut wake = session.next_announce();
+        if tasks.is_empty() {
+            wake = wake.min(session.next_peer_request());
+        }
+        tokio::select! {
+            Some(piece) = done_rx.recv() => {
+                let length = shared.info.piece_len(piece);
+                left -= length;
+                session.add_downloaded(length);
+                session.set_left(left);
+
+                let have = shared.state.lock().unwrap().picker.have().clone();
+                ResumeData::capture(info_hash, have, &shared.storage)
+                    .and_then(|resume| resume.save(&resume_path))
+                    .context("save resume state")?;
+                if left == 0 {
+                    break;
+                }
+            }
+            Some(joined) = tasks.join_next(), if !tasks.is_empty() => {
+                let (addr, result) = joined.context("peer task panicked")?;
+                swarm.disconnected(addr, result);
+            }
+            _ = tokio::time::sleep_until(wake.into()) => {
+                match session.announce_if_due().await {
+                    Ok(Some(peers)) => {
+                        swarm.add(peers);
+                    }
+                    Ok(None) => {}
+                    Err(e) => eprintln!("announce failed: {:#}", e),
+                }
+            }
+        }
+    }
+
+    // Dropping the tasks disconnects from every peer.
+    drop(tasks);
+    if let Err(e) = session.completed().await {
+        eprintln!("announce failed: {:#}", e);
+    }
+    Ok(())
+}
+
+/// The peers we know of and what became of them.
+#[derive(Default)]
+struct Swarm {
+    // Peers we have not tried yet, or may try again.
+    queue: VecDeque<SocketAddr>,
+    // Peers connected right now.
+    connected: BTreeSet<SocketAddr>,
+    // Peers that sent corrupt data or broke the protocol; never tried again.
+    banned: BTreeSet<SocketAddr>,
+}
+
+impl Swarm {
+    /// Queues the peers that are neither connected, queued nor banned, and returns whether
+    /// there were any.
+    fn add(&mut self, peers: Vec<SocketAddr>) -> bool {
+        let before = self.queue.len();
+        for addr in peers {
+            if !self.connected.contains(&addr)
+                && !self.banned.contains(&addr)
+                && !self.queue.contains(&addr)
+            {
+                self.queue.push_back(addr);
+            }
+        }
+        self.queue.len() > before
+    }
+
+    fn next(&mut self) -> Option<SocketAddr> {
+        let addr = self.queue.pop_front()?;
+        self.connected.insert(addr);
+        Some(addr)
+    }
+
+    fn disconnected(&mut self, addr: SocketAddr, result: Result<()>) {
+        self.connected.remove(&addr);
+        match result {
+            Ok(()) => {}
+            Err(e @ (Error::HashMismatch(_) | Error::Protocol(_))) => {
+                eprintln!("peer {}: {}, banned", addr, e);
+                self.banned.insert(addr);
+            }
+            Err(e) => eprintln!("peer {}: {}", addr, e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    // Not received yet, and requested from this many peers.
+    Pending(usize),
+    // Received from this peer.
+    Received(SocketAddr),
+}
+
+/// A piece being downloaded, with several of its blocks requested at a time.
+struct PieceDownload {
+    piece: usize,
+    // The peer that claimed the piece, if any.
+    owner: Option<SocketAddr>,
+    buf: Vec<u8>,
+    blocks: Vec<Block>,
+    // Bytes of the blocks received so far.
+    received: usize,
+}
+
+impl PieceDownload {
+    fn new(info: &Info, piece: usize) -> Self {
+        let length = info.piece_len(piece);
+        Self {
+            piece,
+            owner: None,
+            buf: vec![0; length],
+            blocks: vec![Block::Pending(0); length.div_ceil(BLOCK_MAX as usize)],
+            received: 0,
+        }
+    }
+
+    fn block_len(&self, block: usize) -> usize {
+        let begin = block * BLOCK_MAX as usize;
+        (self.buf.len() - begin).min(BLOCK_MAX as usize)
+    }
+
+    fn block_hash(&self, block: usize) -> [u8; 20] {
+        let begin = block * BLOCK_MAX as usize;
+        Sha1::digest(&self.buf[begin..begin + self.block_len(block)]).into()
+    }
+}
+
+/// A `request` or `cancel` message for a block.
+fn block_message(info: &Info, tag: MessageTag, piece: usize, block: usize) -> Message {
+    let begin = block * BLOCK_MAX as usize;
+    let length = (info.piece_len(piece) - begin).min(BLOCK_MAX as usize);
+    let request = Request::new(piece as u32, begin as u32, length as u32);
+    Message {
+        tag,
+        payload: bincode::serialize(&request).expect("serialize request"),
+    }
+}
+
+/// Talks to one peer until the download is complete or the peer goes away.
+async fn run_peer(shared: Arc<Shared>, addr: SocketAddr) -> Result<()> {
+    let handshake = Handshake::new(shared.info_hash, shared.peer_id).with_extension_protocol();
+    let (mut conn, reply) = tokio::time::timeout(CONNECT_TIMEOUT, peer::connect(addr, &handshake))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    // The bitfield has to be the first message, and is left out when we have nothing.
+    let have = shared.state.lock().unwrap().picker.have().clone();
+    if have.any() {
+        conn.send(have.to_message()).await?;
+    }
+    // The extension handshake tells us how many requests the peer queues (`reqq`).
+    let extensions = Extensions::new();
+    if reply.supports_extension_protocol() {
+        conn.send(extensions.handshake(None).to_message()).await?;
+    }
+
+    let mut peer = PeerState {
+        addr,
+        remote: Bitfield::new(shared.info.pieces.0.len()),
+        choked: true,
+        interested: false,
+        requested: BTreeSet::new(),
+        last_message: Instant::now(),
+        last_block: Instant::now(),
+        window: RequestWindow::new(shared.max_requests),
+        extensions,
+    };
+    let result = peer.run(&shared, &mut conn).await;
+    // Whatever happened, the pieces we were downloading are up for grabs again.
+    shared.release(addr, &peer.requested);
+    shared
+        .state
+        .lock()
+        .unwrap()
+        .picker
+        .remove_peer(&peer.remote);
+    result
+}
+
+struct PeerState {
+    addr: SocketAddr,
+    // Pieces the peer has.
+    remote: Bitfield,
+    // Whether the peer refuses to send us data.
+    choked: bool,
+    // Whether we told the peer we want data.
+    interested: bool,
+    // Blocks requested from the peer and not received yet, as piece and block index.
+    requested: BTreeSet<(usize, usize)>,
+    // When the peer last sent anything.
+    last_message: Instant,
+    // When the peer last sent a block we asked for, or when we asked for blocks while none
+    // were outstanding.
+    last_block: Instant,
+    window: RequestWindow,
+    extensions: Extensions,
+}
+
+impl PeerState {
+    async fn run(
+        &mut self,
+        shared: &Shared,
+        conn: &mut Framed<TcpStream, MessageFramer>,
+    ) -> Result<()> {
+        let mut changed = shared.changed.subscribe();
+        loop {
+            changed.borrow_and_update();
+            if shared.is_complete() {
+                return Ok(());
+            }
+            if let Some(piece) = shared.banned(self.addr) {
+                return Err(Error::HashMismatch(piece));
+            }
+
+            let interested = !self.requested.is_empty() || shared.wants_any(&self.remote);
+            if interested != self.interested {
+                let tag = if interested {
+                    MessageTag::Interested
+                } else {
+                    MessageTag::NotInterested
+                };
+                conn.send(Message {
+                    tag,
+                    payload: vec![],
+                })
+                .await?;
+                self.interested = interested;
+            }
+
+            self.cancel_received(shared, conn).await?;
+            if !self.choked {
+                self.fill_requests(shared, conn).await?;
+            }
+
+            // Waking up for other peers' progress does not reset these deadlines.
+            let idle = self.last_message + IDLE_TIMEOUT;
+            let unanswered = self.last_block + REQUEST_TIMEOUT;
+            let msg = tokio::select! {
+                msg = peer::next_message(conn) => msg?,
+                // Pieces were released or completed, or blocks arrived from other peers; see
+                // if there is something to do now.
+                Ok(()) = changed.changed() => continue,
+                _ = tokio::time::sleep_until(idle.into()) => {
+                    return Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into());
+                }
+                _ = tokio::time::sleep_until(unanswered.into()), if !self.requested.is_empty() => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "requested blocks did not arrive",
+                    )
+                    .into());
+                }
+            };
+            self.last_message = Instant::now();
+            self.handle(shared, msg)?;
+        }
+    }
+
+    /// Cancels the requests for blocks that other peers delivered first.
+    async fn cancel_received(
+        &mut self,
+        shared: &Shared,
+        conn: &mut Framed<TcpStream, MessageFramer>,
+    ) -> Result<()> {
+        let received: Vec<(usize, usize)> = {
+            let state = shared.state.lock().unwrap();
+            self.requested
+                .iter()
+                .copied()
+                .filter(|&(piece, block)| !state.is_wanted(piece, block))
+                .collect()
+        };
+        if received.is_empty() {
+            return Ok(());
+        }
+        for (piece, block) in received {
+            self.requested.remove(&(piece, block));
+            conn.feed(block_message(
+                &shared.info,
+                MessageTag::Cancel,
+                piece,
+                block,
+            ))
+            .await?;
+        }
+        conn.flush().await
+    }
+
+    /// Tops the outstanding requests up to the window, claiming more pieces as needed.
+    async fn fill_requests(
+        &mut self,
+        shared: &Shared,
+        conn: &mut Framed<TcpStream, MessageFramer>,
+    ) -> Result<()> {
+        if self.requested.is_empty() {
+            self.last_block = Instant::now();
+        }
+        let mut blocks = Vec::new();
+        {
+            let mut state = shared.state.lock().unwrap();
+            while self.requested.len() < self.window.size() {
+                let Some(block) =
+                    state.next_block(&shared.info, self.addr, &self.remote, &self.requested)
+                else {
+                    break;
+                };
+                self.requested.insert(block);
+                blocks.push(block);
+            }
+        }
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        for (piece, block) in blocks {
+            conn.feed(block_message(
+                &shared.info,
+                MessageTag::Request,
+                piece,
+                block,
+            ))
+            .await?;
+        }
+        conn.flush().await
+    }
+
+    fn handle(&mut self, shared: &Shared, msg: Message) -> Result<()> {
+        match msg.tag {
+            MessageTag::Bitfield => {
+                let remote = Bitfield::from_bytes(&msg.payload, self.remote.len())?;
+                let mut state = shared.state.lock().unwrap();
+                state.picker.remove_peer(&self.remote);
+                self.remote = remote;
+                state.picker.add_peer(&self.remote);
+            }
+            MessageTag::Have => {
+                let piece = peer::parse_have(&msg.payload, self.remote.len())?;
+                if !self.remote.get(piece) {
+                    self.remote.set(piece, true);
+                    shared.state.lock().unwrap().picker.peer_has(piece);
+                }
+            }
+            MessageTag::Choke => {
+                self.choked = true;
+                // Requests are dropped on choke, so other peers have to finish the pieces.
+                shared.release(self.addr, &self.requested);
+                self.requested.clear();
+            }
+            MessageTag::Unchoke => {
+                self.choked = false;
+                self.window.restart();
+            }
+            MessageTag::Piece => {
+                let response = PieceResponse::from_bytes(&msg.payload)?;
+                let piece = u32::from_be_bytes(response.index) as usize;
+                let begin = u32::from_be_bytes(response.begin) as usize;
+                let block = begin / BLOCK_MAX as usize;
+                // Blocks we did not ask for, or no longer, are leftovers from before a choke
+                // or a cancel.
+                if !begin.is_multiple_of(BLOCK_MAX as usize)
+                    || !self.requested.remove(&(piece, block))
+                {
+                    return Ok(());
+                }
+                self.window.received(response.block.len());
+                self.last_block = Instant::now();
+
+                if let Some(download) = shared.receive(self.addr, piece, block, &response.block)? {
+                    shared.verify(download)?;
+                }
+            }
+            MessageTag::Extended => {
+                self.extensions
+                    .handle(&msg)
+                    .map_err(|e| Error::Protocol(format!("{:#}", e)))?;
+                if let Some(reqq) = self.extensions.remote().and_then(|hs| hs.reqq) {
+                    self.window.limit(reqq);
+                }
+            }
+            // We do not upload, so requests and the like are ignored.
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{hashes::Hashes, Keys};
+
+    const BLOCK: usize = BLOCK_MAX as usize;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    /// The engine state for a single file of `data`, in pieces of `blocks` blocks each, none of
+    /// which we have yet. Verified pieces go to a file in the returned directory.
+    fn shared(blocks: usize, data: &[u8]) -> (Shared, tempfile::TempDir) {
+        let piece_length = blocks * BLOCK;
+        let info = Info {
+            name: "test".to_string(),
+            piece_length,
+            pieces: Hashes(
+                data.chunks(piece_length)
+                    .map(|piece| Sha1::digest(piece).into())
+                    .collect(),
+            ),
+            keys: Keys::SingleFile { length: data.len() },
+            private: None,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(&info, &dir.path().join("test")).unwrap();
+        let num_pieces = info.pieces.0.len();
+        let shared = Shared {
+            info_hash: [0; 20],
+            peer_id: PeerId([0; 20]),
+            info,
+            storage,
+            max_requests: 5,
+            state: Mutex::new(State {
+                picker: PiecePicker::new(Bitfield::new(num_pieces)).random_first(0),
+                downloads: BTreeMap::new(),
+                failed: BTreeMap::new(),
+                banned: BTreeMap::new(),
+            }),
+            changed: watch::channel(()).0,
+            done: mpsc::unbounded_channel().0,
+        };
+        (shared, dir)
+    }
+
+    impl Shared {
+        fn next_block(
+            &self,
+            addr: SocketAddr,
+            remote: &Bitfield,
+            requested: &BTreeSet<(usize, usize)>,
+        ) -> Option<(usize, usize)> {
+            let mut state = self.state.lock().unwrap();
+            state.next_block(&self.info, addr, remote, requested)
+        }
+
+        fn blocks(&self, piece: usize) -> Option<Vec<Block>> {
+            let state = self.state.lock().unwrap();
+            state.downloads.get(&piece).map(|d| d.blocks.clone())
+        }
+    }
+
+    #[test]
+    fn release_keeps_received_blocks() {
+        let data = vec![1; 3 * BLOCK];
+        let (shared, _dir) = shared(3, &data);
+        let all = Bitfield::from_iter([true]);
+        let (a, b) = (addr(1), addr(2));
+
+        let requested = BTreeSet::from([(0, 0), (0, 1)]);
+        assert_eq!(shared.next_block(a, &all, &BTreeSet::new()), Some((0, 0)));
+        assert_eq!(shared.next_block(a, &all, &BTreeSet::new()), Some((0, 1)));
+        assert!(shared.receive(a, 0, 0, &data[..BLOCK]).unwrap().is_none());
+        shared.release(a, &requested);
+        assert_eq!(
+            shared.blocks(0).unwrap(),
+            [Block::Received(a), Block::Pending(0), Block::Pending(0)]
+        );
+
+        // The next peer only downloads the rest.
+        assert_eq!(shared.next_block(b, &all, &BTreeSet::new()), Some((0, 1)));
+        assert_eq!(shared.next_block(b, &all, &BTreeSet::new()), Some((0, 2)));
+        shared.receive(b, 0, 1, &data[BLOCK..2 * BLOCK]).unwrap();
+        let download = shared
+            .receive(b, 0, 2, &data[2 * BLOCK..])
+            .unwrap()
+            .unwrap();
+        assert_eq!(download.buf, data);
+        shared.verify(download).unwrap();
+        assert!(shared.is_complete());
+    }
+
+    #[test]
+    fn release_drops_pieces_without_received_blocks() {
+        let (shared, _dir) = shared(2, &[1; 2 * BLOCK]);
+        let all = Bitfield::from_iter([true]);
+        let a = addr(1);
+
+        assert_eq!(shared.next_block(a, &all, &BTreeSet::new()), Some((0, 0)));
+        shared.release(a, &BTreeSet::from([(0, 0)]));
+        assert_eq!(shared.blocks(0), None);
+        assert_eq!(
+            shared.next_block(addr(2), &all, &BTreeSet::new()),
+            Some((0, 0))
+        );
+    }
+
+    #[test]
+    fn blames_a_single_source_of_a_bad_piece() {
+        let (shared, _dir) = shared(2, &[1; 2 * BLOCK]);
+        let all = Bitfield::from_iter([true]);
+        let a = addr(1);
+
+        shared.next_block(a, &all, &BTreeSet::new());
+        shared.next_block(a, &all, &BTreeSet::new());
+        shared.receive(a, 0, 0, &[0; BLOCK]).unwrap();
+        let download = shared.receive(a, 0, 1, &[1; BLOCK]).unwrap().unwrap();
+        assert!(matches!(
+            shared.verify(download),
+            Err(Error::HashMismatch(0))
+        ));
+        assert!(shared.state.lock().unwrap().failed.is_empty());
+        assert!(!shared.is_complete());
+    }
+
+    #[test]
+    fn bans_the_sender_of_a_bad_block_once_the_piece_verifies() {
+        let data = vec![1; 2 * BLOCK];
+        let (shared, _dir) = shared(2, &data);
+        let all = Bitfield::from_iter([true]);
+        let (a, b) = (addr(1), addr(2));
+
+        // In the endgame, `b` sends a bad copy of a block `a` was asked for.
+        shared.next_block(a, &all, &BTreeSet::new());
+        shared.next_block(a, &all, &BTreeSet::new());
+        assert_eq!(shared.next_block(b, &all, &BTreeSet::new()), Some((0, 0)));
+        shared.receive(b, 0, 0, &[0; BLOCK]).unwrap();
+        let download = shared.receive(a, 0, 1, &data[BLOCK..]).unwrap().unwrap();
+        // Neither peer sent all of it, so neither is to blame yet.
+        shared.verify(download).unwrap();
+        assert_eq!(shared.banned(a), None);
+        assert_eq!(shared.banned(b), None);
+
+        // Once `a` sends all of it and it verifies, `b` is found out.
+        shared.next_block(a, &all, &BTreeSet::new());
+        shared.next_block(a, &all, &BTreeSet::new());
+        shared.receive(a, 0, 0, &data[..BLOCK]).unwrap();
+        let download = shared.receive(a, 0, 1, &data[BLOCK..]).unwrap().unwrap();
+        shared.verify(download).unwrap();
+        assert!(shared.is_complete());
+        assert_eq!(shared.banned(a), None);
+        assert_eq!(shared.banned(b), Some(0));
+        assert_eq!(std::fs::read(&shared.storage.paths()[0]).unwrap(), data);
+    }
+
+    #[test]
+    fn duplicates_the_least_requested_block_in_the_endgame() {
+        let (shared, _dir) = shared(3, &[1; 4 * BLOCK]);
+        let (a, b, c) = (addr(1), addr(2), addr(3));
+        let first = Bitfield::from_iter([true, false]);
+        let all = Bitfield::from_iter([true, true]);
+
+        let mut from_a = BTreeSet::new();
+        for block in 0..3 {
+            assert_eq!(shared.next_block(a, &first, &from_a), Some((0, block)));
+            from_a.insert((0, block));
+        }
+        // Piece 1 is not claimed yet, so `b` gets no duplicates of piece 0.
+        assert_eq!(shared.next_block(b, &first, &BTreeSet::new()), None);
+        assert_eq!(shared.next_block(c, &all, &BTreeSet::new()), Some((1, 0)));
+        assert_eq!(shared.next_block(a, &first, &from_a), None);
+
+        // Every piece is claimed now: each block goes to whoever has asked for it the fewest
+        // times, and never twice to the same peer.
+        let mut from_b = BTreeSet::new();
+        assert_eq!(shared.next_block(b, &first, &from_b), Some((0, 0)));
+        from_b.insert((0, 0));
+        assert_eq!(shared.next_block(c, &first, &BTreeSet::new()), Some((0, 1)));
+        assert_eq!(shared.next_block(b, &first, &from_b), Some((0, 2)));
+        from_b.insert((0, 2));
+        assert_eq!(shared.next_block(b, &first, &from_b), Some((0, 1)));
+        assert_eq!(
+            shared.blocks(0).unwrap(),
+            [Block::Pending(2), Block::Pending(3), Block::Pending(2)]
+        );
+
+        // A block received from one peer is no longer worth asking for, and a cancelled request
+        // makes its block the least requested again.
+        shared.receive(b, 0, 0, &[1; BLOCK]).unwrap();
+        assert!(!shared.state.lock().unwrap().is_wanted(0, 0));
+        shared.state.lock().unwrap().unrequest(0, 2);
+        assert_eq!(
+            shared.next_block(addr(4), &first, &BTreeSet::new()),
+            Some((0, 2))
+        );
+    }
+
+    #[test]
+    fn downloads_failed_pieces_from_a_single_peer() {
+        let data = vec![1; 2 * BLOCK];
+        let (shared, _dir) = shared(2, &data);
+        let all = Bitfield::from_iter([true]);
+        let (a, b) = (addr(1), addr(2));
+
+        shared.next_block(a, &all, &BTreeSet::new());
+        shared.next_block(a, &all, &BTreeSet::new());
+        shared.next_block(b, &all, &BTreeSet::new());
+        shared.receive(b, 0, 0, &[0; BLOCK]).unwrap();
+        let download = shared.receive(a, 0, 1, &data[BLOCK..]).unwrap().unwrap();
+        shared.verify(download).unwrap();
+
+        // Whoever claims the piece next gets all of it, even in the endgame.
+        let from_b = BTreeSet::from([(0, 0), (0, 1)]);
+        assert_eq!(shared.next_block(b, &all, &BTreeSet::new()), Some((0, 0)));
+        assert_eq!(shared.next_block(b, &all, &BTreeSet::new()), Some((0, 1)));
+        assert_eq!(shared.next_block(a, &all, &BTreeSet::new()), None);
+
+        // If that peer goes away, what it sent is dropped along with the piece.
+        shared.receive(b, 0, 0, &data[..BLOCK]).unwrap();
+        shared.release(b, &from_b);
+        assert_eq!(shared.blocks(0), None);
+        assert_eq!(shared.next_block(a, &all, &BTreeSet::new()), Some((0, 0)));
+    }
+}
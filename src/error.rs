@@ -0,0 +1,28 @@
+//! Errors of the peer wire protocol, the trackers and the download path, so that a misbehaving
+//! peer ends its connection instead of the process.
+use crate::{peer::MessageTag, tracker::TrackerError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    // A downloaded piece does not match its hash from the info dictionary.
+    #[error("piece {0} does not match its hash")]
+    HashMismatch(usize),
+    // The peer sent a message that makes no sense at this point of the exchange.
+    #[error("expected a {expected:?} message, peer sent {actual:?}")]
+    UnexpectedMessage {
+        expected: MessageTag,
+        actual: MessageTag,
+    },
+    #[error("peer closed the connection")]
+    PeerDisconnected,
+    // The peer broke the wire protocol, e.g. with a malformed message.
+    #[error("protocol violation: {0}")]
+    Protocol(String),
+    // A tracker could not be asked or turned the request down.
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
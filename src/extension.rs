@@ -0,0 +1,250 @@
+//! The extension protocol (BEP 10).
+//!
+//! Peers that set the extension bit in their handshake can exchange `extended` messages (id 20).
+//! The first byte of their payload is the extended message id: 0 is the extension handshake, any
+//! other id is an extension message whose meaning was agreed on in the handshakes.
+use anyhow::Context;
+use std::{any::Any, collections::BTreeMap, net::IpAddr};
+
+use crate::{
+    bencode::{self, Value},
+    peer::{Message, MessageTag},
+};
+
+/// Extended message id of the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The bencoded dictionary peers send each other right after the BitTorrent handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionHandshake {
+    // Maps the name of every supported extension to the extended message id the sender wants to
+    // receive it with.
+    pub m: BTreeMap<String, u8>,
+    // Size of the info dictionary in bytes, sent by peers supporting ut_metadata (BEP 9).
+    pub metadata_size: Option<usize>,
+    // Client name and version.
+    pub v: Option<String>,
+    // Local TCP listen port, so the other side can connect back.
+    pub p: Option<u16>,
+    // Number of outstanding request messages the client supports without dropping any.
+    pub reqq: Option<usize>,
+    // The IP address the sender sees the receiver at, sent as 4 or 16 bytes.
+    pub yourip: Option<IpAddr>,
+}
+
+impl ExtensionHandshake {
+    pub fn from_bytes(payload: &[u8]) -> anyhow::Result<Self> {
+        let value = bencode::decode(payload).context("decode extension handshake")?;
+        anyhow::ensure!(
+            value.as_dict().is_some(),
+            "extension handshake is not a dictionary"
+        );
+        let m = value
+            .get("m")
+            .and_then(Value::as_dict)
+            .map(|m| {
+                m.iter()
+                    .filter_map(|(name, id)| {
+                        let name = String::from_utf8(name.clone()).ok()?;
+                        let id = u8::try_from(id.as_int()?).ok()?;
+                        Some((name, id))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Self {
+            m,
+            metadata_size: value
+                .get("metadata_size")
+                .and_then(Value::as_int)
+                .and_then(|n| usize::try_from(n).ok()),
+            v: value.get("v").and_then(Value::as_str).map(String::from),
+            p: value
+                .get("p")
+                .and_then(Value::as_int)
+                .and_then(|n| u16::try_from(n).ok()),
+            reqq: value
+                .get("reqq")
+                .and_then(Value::as_int)
+                .and_then(|n| usize::try_from(n).ok()),
+            yourip: value
+                .get("yourip")
+                .and_then(Value::as_bytes)
+                .and_then(|ip| match ip.len() {
+                    4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?)),
+                    16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?)),
+                    _ => None,
+                }),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let m = self
+            .m
+            .iter()
+            .map(|(name, &id)| (name.as_bytes().to_vec(), Value::Int(id as i64)))
+            .collect();
+        let mut dict = BTreeMap::from([(b"m".to_vec(), Value::Dict(m))]);
+        if let Some(metadata_size) = self.metadata_size {
+            dict.insert(b"metadata_size".to_vec(), Value::Int(metadata_size as i64));
+        }
+        if let Some(v) = &self.v {
+            dict.insert(b"v".to_vec(), Value::from(v.as_str()));
+        }
+        if let Some(p) = self.p {
+            dict.insert(b"p".to_vec(), Value::Int(p as i64));
+        }
+        if let Some(reqq) = self.reqq {
+            dict.insert(b"reqq".to_vec(), Value::Int(reqq as i64));
+        }
+        if let Some(yourip) = self.yourip {
+            let ip = match yourip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            dict.insert(b"yourip".to_vec(), Value::Bytes(ip));
+        }
+        bencode::encode(&Value::Dict(dict))
+    }
+
+    pub fn to_message(&self) -> Message {
+        message(HANDSHAKE_ID, &self.to_bytes())
+    }
+}
+
+/// Wraps `payload` into an extended message with extended message id `id`.
+pub fn message(id: u8, payload: &[u8]) -> Message {
+    let mut buf = Vec::with_capacity(1 + payload.len());
+    buf.push(id);
+    buf.extend_from_slice(payload);
+    Message {
+        tag: MessageTag::Extended,
+        payload: buf,
+    }
+}
+
+/// Splits an extended message into its extended message id and payload.
+pub fn split(msg: &Message) -> Option<(u8, &[u8])> {
+    if msg.tag != MessageTag::Extended {
+        return None;
+    }
+    msg.payload
+        .split_first()
+        .map(|(&id, payload)| (id, payload))
+}
+
+/// An extension that can be plugged into [`Extensions`] under the name it is advertised with in
+/// the `m` dictionary.
+pub trait ExtensionHandler: Any + Send {
+    /// Name of the extension, e.g. `ut_metadata`.
+    fn name(&self) -> &'static str;
+
+    /// Adds the extension's own fields (e.g. `metadata_size`) to our extension handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtensionHandshake) {}
+
+    /// Called with the peer's extension handshake. `id` is the extended message id the peer
+    /// wants to receive this extension's messages with, or `None` if it does not support it.
+    /// Returns the messages to send in response.
+    fn on_handshake(
+        &mut self,
+        _handshake: &ExtensionHandshake,
+        _id: Option<u8>,
+    ) -> anyhow::Result<Vec<Message>> {
+        Ok(Vec::new())
+    }
+
+    /// Handles the payload of a message the peer sent for this extension and returns the
+    /// messages to send in response.
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Message>>;
+}
+
+/// The extensions enabled on a connection, which assigns every registered handler the extended
+/// message id we receive its messages with and routes incoming extended messages to it.
+#[derive(Default)]
+pub struct Extensions {
+    // Handler i receives the messages sent with extended message id i + 1.
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    // The peer's extension handshake, once received.
+    remote: Option<ExtensionHandshake>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `handler` and returns the extended message id assigned to it.
+    pub fn register(&mut self, handler: impl ExtensionHandler) -> u8 {
+        assert!(
+            self.handlers.len() < u8::MAX as usize,
+            "too many extensions"
+        );
+        self.handlers.push(Box::new(handler));
+        self.handlers.len() as u8
+    }
+
+    /// Returns the registered handler of type `H`.
+    pub fn get<H: ExtensionHandler>(&self) -> Option<&H> {
+        self.handlers
+            .iter()
+            .find_map(|h| (h.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    /// Returns the registered handler of type `H` mutably.
+    pub fn get_mut<H: ExtensionHandler>(&mut self) -> Option<&mut H> {
+        self.handlers
+            .iter_mut()
+            .find_map(|h| (h.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Builds our extension handshake, advertising every registered extension.
+    pub fn handshake(&self, v: Option<String>) -> ExtensionHandshake {
+        let mut handshake = ExtensionHandshake {
+            v,
+            ..Default::default()
+        };
+        for (i, handler) in self.handlers.iter().enumerate() {
+            handshake.m.insert(handler.name().to_string(), i as u8 + 1);
+            handler.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    /// The peer's extension handshake, if it has been received.
+    pub fn remote(&self) -> Option<&ExtensionHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// The extended message id the peer wants to receive extension `name` with.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote
+            .as_ref()?
+            .m
+            .get(name)
+            .copied()
+            .filter(|&id| id != 0)
+    }
+
+    /// Handles an extended message from the peer and returns the messages to send in response.
+    /// Messages for extensions that were not registered are ignored.
+    pub fn handle(&mut self, msg: &Message) -> anyhow::Result<Vec<Message>> {
+        let Some((id, payload)) = split(msg) else {
+            return Ok(Vec::new());
+        };
+        if id == HANDSHAKE_ID {
+            let remote = ExtensionHandshake::from_bytes(payload)?;
+            let mut replies = Vec::new();
+            for handler in &mut self.handlers {
+                // An id of 0 disables an extension the peer advertised before.
+                let id = remote.m.get(handler.name()).copied().filter(|&id| id != 0);
+                replies.extend(handler.on_handshake(&remote, id)?);
+            }
+            self.remote = Some(remote);
+            return Ok(replies);
+        }
+        match self.handlers.get_mut(id as usize - 1) {
+            Some(handler) => handler.on_message(payload),
+            None => Ok(Vec::new()),
+        }
+    }
+}
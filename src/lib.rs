@@ -1,3 +1,18 @@
+pub mod bencode;
+pub mod bitfield;
+pub mod builder;
+pub mod engine;
+pub mod error;
+pub mod extension;
+pub mod magnet;
+pub mod metadata;
 pub mod peer;
+pub mod picker;
+pub mod pipeline;
+pub mod resume;
+pub mod storage;
 pub mod torrent;
 pub mod tracker;
+pub mod verify;
+
+pub use error::{Error, Result};
@@ -0,0 +1,298 @@
+//! Magnet links (BEP 9), which identify a torrent by its info hash instead of a metainfo file.
+//!
+//! A magnet link looks like `magnet:?xt=urn:btih:<info-hash>&dn=<name>&tr=<tracker-url>`, where
+//! the info hash is either 40 hex digits or 32 base32 characters. Supported parameters:
+//! - `xt`: exact topic, the `urn:btih:` info hash (required)
+//! - `dn`: display name
+//! - `tr`: tracker URL, may be repeated
+//! - `ws`: web seed URL (BEP 19), may be repeated
+//! - `x.pe`: address of a peer to connect to, may be repeated
+//! - `so`: select only these file indices (BEP 53), e.g. `0,2,4-6`
+use anyhow::Context;
+use std::{fmt, ops::RangeInclusive, str::FromStr};
+
+use crate::torrent::Torrent;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    pub web_seeds: Vec<String>,
+    // Peer addresses as given, `<host>:<port>`.
+    pub peers: Vec<String>,
+    // Indices of the files to download; empty means all of them.
+    pub select_only: Vec<RangeInclusive<usize>>,
+}
+
+impl MagnetLink {
+    /// Builds a magnet link for `torrent`, listing all of its trackers.
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        let mut trackers = Vec::new();
+        for url in torrent.trackers().into_iter().flatten() {
+            if !trackers.contains(&url) {
+                trackers.push(url);
+            }
+        }
+        Self {
+            info_hash: torrent.info_hash(),
+            display_name: Some(torrent.info.name.clone()),
+            trackers,
+            web_seeds: Vec::new(),
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = s
+            .strip_prefix("magnet:?")
+            .context("magnet link must start with magnet:?")?;
+
+        let mut info_hash = None;
+        let mut link = MagnetLink {
+            info_hash: [0; 20],
+            display_name: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        };
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            // Names are usually form-encoded, with spaces written as `+`.
+            let value = if key == "dn" {
+                value.replace('+', "%20")
+            } else {
+                value.to_string()
+            };
+            let value = urlencoding::decode(&value)
+                .with_context(|| format!("invalid percent-encoding in {}", key))?
+                .into_owned();
+            // Parameters may carry a numeric suffix to tell several of them apart, e.g. `tr.1`.
+            let key = match key.rsplit_once('.') {
+                Some((base, n)) if n.parse::<u32>().is_ok() => base,
+                _ => key,
+            };
+            match key {
+                "xt" => {
+                    // Other kinds of topic (e.g. `urn:btmh:` for v2 torrents) are skipped.
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        if info_hash.is_none() {
+                            info_hash = Some(parse_info_hash(hash)?);
+                        }
+                    }
+                }
+                "dn" => link.display_name = Some(value),
+                "tr" => link.trackers.push(value),
+                "ws" => link.web_seeds.push(value),
+                "x.pe" => link.peers.push(value),
+                "so" => link.select_only = parse_select_only(&value)?,
+                // Unknown parameters are ignored, as the BEP asks.
+                _ => {}
+            }
+        }
+        link.info_hash = info_hash.context("magnet link has no urn:btih: exact topic")?;
+        Ok(link)
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "magnet:?xt=urn:btih:{}", hex::encode(self.info_hash))?;
+        if let Some(name) = &self.display_name {
+            write!(f, "&dn={}", urlencoding::encode(name))?;
+        }
+        for tracker in &self.trackers {
+            write!(f, "&tr={}", urlencoding::encode(tracker))?;
+        }
+        for web_seed in &self.web_seeds {
+            write!(f, "&ws={}", urlencoding::encode(web_seed))?;
+        }
+        for peer in &self.peers {
+            write!(f, "&x.pe={}", urlencoding::encode(peer))?;
+        }
+        if !self.select_only.is_empty() {
+            f.write_str("&so=")?;
+            for (i, range) in self.select_only.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(",")?;
+                }
+                if range.start() == range.end() {
+                    write!(f, "{}", range.start())?;
+                } else {
+                    write!(f, "{}-{}", range.start(), range.end())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_info_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("invalid hex info hash")?,
+        32 => base32_decode(hash).context("invalid base32 info hash")?,
+        n => anyhow::bail!(
+            "info hash must be 40 hex or 32 base32 characters, got {}",
+            n
+        ),
+    };
+    Ok(bytes.try_into().expect("decoded info hash is 20 bytes"))
+}
+
+/// Decodes unpadded RFC 4648 base32, case-insensitively.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn parse_select_only(value: &str) -> anyhow::Result<Vec<RangeInclusive<usize>>> {
+    value
+        .split(',')
+        .map(|item| {
+            let (start, end) = item.split_once('-').unwrap_or((item, item));
+            let start = start
+                .parse()
+                .with_context(|| format!("invalid so {:?}", item))?;
+            let end = end
+                .parse()
+                .with_context(|| format!("invalid so {:?}", item))?;
+            anyhow::ensure!(start <= end, "invalid so range {:?}", item);
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "b257af45fbc9f52d0e728490f2c2da9e4f66647e";
+    const BASE32: &str = "WJL26RP3ZH2S2DTSQSIPFQW2TZHWMZD6";
+
+    fn info_hash() -> [u8; 20] {
+        hex::decode(HEX).unwrap().try_into().unwrap()
+    }
+
+    fn parse(s: &str) -> MagnetLink {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_hex_and_base32_info_hashes_in_either_case() {
+        for hash in [
+            HEX.to_string(),
+            HEX.to_uppercase(),
+            BASE32.to_string(),
+            BASE32.to_lowercase(),
+        ] {
+            let link = parse(&format!("magnet:?xt=urn:btih:{}", hash));
+            assert_eq!(link.info_hash, info_hash(), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_info_hashes() {
+        for hash in [
+            &HEX[1..],
+            &BASE32[1..],
+            &format!("{}00", HEX),
+            "",
+            &HEX.replace('b', "g"),
+        ] {
+            let link = format!("magnet:?xt=urn:btih:{}", hash);
+            assert!(link.parse::<MagnetLink>().is_err(), "{}", link);
+        }
+        // Neither 0, 1, 8 nor 9 is a base32 digit.
+        let link = format!("magnet:?xt=urn:btih:{}0", &BASE32[1..]);
+        assert!(link.parse::<MagnetLink>().is_err());
+        assert!("magnet:?dn=x".parse::<MagnetLink>().is_err());
+        assert!(format!("http://x/?xt=urn:btih:{}", HEX)
+            .parse::<MagnetLink>()
+            .is_err());
+    }
+
+    #[test]
+    fn decodes_base32() {
+        assert_eq!(base32_decode("MZXW6YTBOI").unwrap(), b"foobar");
+        assert_eq!(base32_decode("mzxw6ytboi").unwrap(), b"foobar");
+        assert_eq!(base32_decode("").unwrap(), b"");
+        assert_eq!(base32_decode("MZXW6=="), None);
+    }
+
+    #[test]
+    fn parses_every_parameter() {
+        let link = parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=Some+file%21&tr.1=http%3A%2F%2Fa%2Fannounce\
+             &tr.2=udp%3A%2F%2Fb%3A80&ws=http%3A%2F%2Fw%2Ff&x.pe=10.0.0.1%3A6881\
+             &so=0,2,4-6&unknown=1",
+            HEX
+        ));
+        assert_eq!(link.display_name.as_deref(), Some("Some file!"));
+        assert_eq!(link.trackers, ["http://a/announce", "udp://b:80"]);
+        assert_eq!(link.web_seeds, ["http://w/f"]);
+        assert_eq!(link.peers, ["10.0.0.1:6881"]);
+        assert_eq!(link.select_only, [0..=0, 2..=2, 4..=6]);
+    }
+
+    #[test]
+    fn keeps_plus_outside_the_name() {
+        let link = parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=a+b%2Bc&tr=http%3A%2F%2Fa%2F%3Fk%3Da+b",
+            HEX
+        ));
+        assert_eq!(link.display_name.as_deref(), Some("a b+c"));
+        assert_eq!(link.trackers, ["http://a/?k=a+b"]);
+    }
+
+    #[test]
+    fn rejects_bad_select_only() {
+        for so in ["", "a", "1-", "3-1", "1,,2", "-1"] {
+            let link = format!("magnet:?xt=urn:btih:{}&so={}", HEX, so);
+            assert!(link.parse::<MagnetLink>().is_err(), "{}", link);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let link = MagnetLink {
+            info_hash: info_hash(),
+            display_name: Some("name with spaces & symbols+/".to_string()),
+            trackers: vec![
+                "http://a/announce?x=1&y=2".to_string(),
+                "udp://b:80".to_string(),
+            ],
+            web_seeds: vec!["http://w/f".to_string()],
+            peers: vec!["[::1]:6881".to_string()],
+            select_only: vec![0..=0, 3..=7],
+        };
+        let text = link.to_string();
+        assert!(text.starts_with(&format!("magnet:?xt=urn:btih:{}&dn=", HEX)));
+        assert!(text.ends_with("&so=0,3-7"));
+        assert_eq!(parse(&text), link);
+
+        let bare = parse(&format!("magnet:?xt=urn:btih:{}", BASE32));
+        assert_eq!(bare.to_string(), format!("magnet:?xt=urn:btih:{}", HEX));
+        assert_eq!(parse(&bare.to_string()), bare);
+    }
+}
@@ -1,28 +1,33 @@
 use anyhow::Context;
 use bittorrent_starter_rust::{
-    peer::{Handshake, Message, MessageFramer, MessageTag, PieceResponse, Request},
+    bencode,
+    magnet::MagnetLink,
+    metadata,
+    peer::{self, Handshake, PeerId},
+    pipeline,
+    storage::FsStorage,
     torrent::*,
-    tracker::{urlencode, TrackerRequest, TrackerResponse},
+    tracker::{TrackerClient, TrackerError, TrackerList, TrackerSession},
+    verify,
 };
 use clap::{Parser, Subcommand};
-use futures_util::{SinkExt, StreamExt};
-use hashes::Hashes;
-use rand::Rng;
-use reqwest;
-use serde::{self, Deserialize, Serialize};
-use serde_json;
-use sha1::{Digest, Sha1};
 use std::{
-    fmt::format,
-    net::SocketAddrV4,
-    path::{Path, PathBuf},
-    str::FromStr,
+    collections::BTreeMap, io::Write, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration,
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// How long a peer gets to complete the handshake and unchoke us before the next one is tried.
+const PEER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a tracker gets to answer a scrape, retransmissions included.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[clap(rename_all = "snake_case")]
 struct Args {
+    /// Peer id to use, as 20 characters or 40 hex digits; random for every run by default
+    #[arg(long, global = true)]
+    peer_id: Option<PeerId>,
     /// Name of the person to greet
     #[command(subcommand)]
     command: Command,
@@ -34,8 +39,23 @@ enum Command {
     Decode {
         value: String,
     },
+    /// Encode a JSON value as bencode and write it to stdout
+    Encode {
+        json: String,
+    },
     Info {
         torrent: PathBuf,
+        /// Print a magnet link for the torrent instead
+        #[arg(long)]
+        magnet: bool,
+    },
+    /// Show what a magnet link contains
+    Magnet {
+        link: String,
+    },
+    /// Check that a torrent file is canonical bencode, so its info hash is stable across clients
+    Validate {
+        torrent: PathBuf,
     },
     Peers {
         torrent: PathBuf,
@@ -47,13 +67,53 @@ enum Command {
     DownloadPiece {
         #[arg(short)]
         output: PathBuf,
-        torrent: PathBuf,
+        /// Torrent file or magnet link
+        torrent: String,
         piece: usize,
+        /// Most requests to keep outstanding with the peer
+        #[arg(long, default_value_t = pipeline::DEFAULT_MAX_REQUESTS)]
+        max_requests: usize,
     },
     Download {
         #[arg(short)]
         output: PathBuf,
+        /// Torrent file or magnet link
+        torrent: String,
+        /// Most requests to keep outstanding with each peer
+        #[arg(long, default_value_t = pipeline::DEFAULT_MAX_REQUESTS)]
+        max_requests: usize,
+    },
+    /// Create a torrent file from a file or a directory
+    Create {
+        path: PathBuf,
+        #[arg(short)]
+        output: PathBuf,
+        /// Tracker URL; repeat for more tiers, separate trackers of one tier with commas
+        #[arg(short, long)]
+        announce: Vec<String>,
+        /// Piece length in bytes, picked from the total size by default
+        #[arg(long)]
+        piece_length: Option<usize>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long, default_value = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))]
+        created_by: String,
+        /// Leave out the creation date, so the output only depends on the data
+        #[arg(long)]
+        no_creation_date: bool,
+        #[arg(long)]
+        private: bool,
+    },
+    /// Check existing data against the piece hashes of a torrent
+    Verify {
         torrent: PathBuf,
+        /// Where the data lives, as passed to `download -o`
+        path: PathBuf,
+    },
+    /// Ask the trackers how many seeders and leechers torrents have
+    Scrape {
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
     },
 }
 
@@ -61,26 +121,44 @@ enum Command {
 // Usage: your_bittorrent.sh decode "<encoded_value>"
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    let peer_id = args.peer_id.unwrap_or_else(PeerId::random);
 
     match args.command {
         Command::Decode { value } => {
-            let v = decode_bencoded_value(&value);
-            println!("{}", v.0);
+            let v = bencode::decode(value.as_bytes()).context("decode bencoded value")?;
+            println!("{}", v.to_json());
+        }
+        Command::Encode { json } => {
+            let json: serde_json::Value = serde_json::from_str(&json).context("parse JSON")?;
+            let v = bencode::Value::from_json(&json).context("convert JSON to bencode")?;
+            std::io::stdout()
+                .write_all(&bencode::encode(&v))
+                .context("write bencoded value")?;
         }
-        Command::Info { torrent } => {
+        Command::Info { torrent, magnet } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let torrent: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let torrent = Torrent::from_bytes(&dot_torrent)?;
+            if magnet {
+                println!("{}", MagnetLink::from_torrent(&torrent));
+                return Ok(());
+            }
 
             let info_hash = torrent.info_hash();
 
             println!("Tracker URL: {}", torrent.announce);
-            let length = if let Keys::SingleFile { length } = torrent.info.keys {
-                length
-            } else {
-                0
-            };
-            println!("Length: {}", length);
+            if torrent.announce_list.is_some() {
+                println!("Tracker Tiers:");
+                for (i, tier) in torrent.trackers().iter().enumerate() {
+                    println!("{}: {}", i, tier.join(" "));
+                }
+            }
+            println!("Length: {}", torrent.info.length());
+            if let Keys::MultiFile { .. } = torrent.info.keys {
+                println!("Files:");
+                for file in torrent.info.files()? {
+                    println!("{} ({} bytes)", file.path.display(), file.length);
+                }
+            }
             println!("Info Hash: {}", hex::encode(info_hash));
             println!("Piece Length: {}", torrent.info.piece_length);
             println!("Piece Hashes:");
@@ -88,127 +166,127 @@ async fn main() -> anyhow::Result<()> {
                 println!("{}", hex::encode(piece));
             }
         }
+        Command::Magnet { link } => {
+            let link: MagnetLink = link.parse().context("parse magnet link")?;
+            println!("Info Hash: {}", hex::encode(link.info_hash));
+            if let Some(name) = &link.display_name {
+                println!("Name: {}", name);
+            }
+            for tracker in &link.trackers {
+                println!("Tracker URL: {}", tracker);
+            }
+            for web_seed in &link.web_seeds {
+                println!("Web Seed: {}", web_seed);
+            }
+            for peer in &link.peers {
+                println!("Peer: {}", peer);
+            }
+            if !link.select_only.is_empty() {
+                let files: Vec<String> = link
+                    .select_only
+                    .iter()
+                    .flat_map(|r| r.clone())
+                    .map(|i| i.to_string())
+                    .collect();
+                println!("Selected Files: {}", files.join(","));
+            }
+        }
+        Command::Validate { torrent } => {
+            let dot_torrent = std::fs::read(&torrent).context("read torrent file")?;
+            let violations = bencode::validate(&dot_torrent).context("parse torrent file")?;
+            for violation in &violations {
+                println!("{}", violation);
+            }
+            if !violations.is_empty() {
+                anyhow::bail!(
+                    "{} is not canonical bencode ({} violations), its info hash may differ between clients",
+                    torrent.display(),
+                    violations.len()
+                );
+            }
+            println!("{} is canonical bencode", torrent.display());
+        }
         Command::Peers { torrent } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let torrent: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let torrent = Torrent::from_bytes(&dot_torrent)?;
 
             let info_hash = torrent.info_hash();
-            let length = if let Keys::SingleFile { length } = torrent.info.keys {
-                length
-            } else {
-                0
-            };
-
-            let peers: Vec<SocketAddrV4> =
-                torrent.get_peers(&info_hash).await.context("get peers")?;
+            let peers: Vec<SocketAddr> = torrent
+                .get_peers(&info_hash, peer_id)
+                .await
+                .context("get peers")?;
             for peer in &peers {
-                println!("{}:{}", peer.ip(), peer.port());
+                println!("{}", peer);
             }
         }
         Command::Handshake { torrent, peer } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let torrent: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let torrent = Torrent::from_bytes(&dot_torrent)?;
 
             let info_hash = torrent.info_hash();
-            let peer = SocketAddrV4::from_str(&peer).context("parse peer address")?;
-            let mut peer = tokio::net::TcpStream::connect(peer)
+            let peer = SocketAddr::from_str(&peer).context("parse peer address")?;
+            let handshake = Handshake::new(info_hash, peer_id);
+            let (_, handshake) = peer::connect(peer, &handshake)
                 .await
-                .context("connect to peer")?;
-
-            let handshake = Handshake::new(info_hash, *b"00112233445566778899");
-
-            peer.write_all(&bincode::serialize(&handshake).unwrap())
-                .await?;
-
-            let mut buf = [0; 100000];
-            peer.read(&mut buf).await?;
-
-            let handshake: Handshake = bincode::deserialize(&buf).unwrap();
-
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {}", hex::encode(&handshake.peer_id));
+                .context("handshake with peer")?;
+            println!("Peer ID: {}", hex::encode(handshake.peer_id));
         }
         Command::DownloadPiece {
             output,
             torrent,
             piece,
+            max_requests,
         } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let torrent: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let torrent = load_torrent(&torrent, peer_id).await?;
             // eprintln!("torrent: {:?}", torrent);
 
             let info_hash = torrent.info_hash();
-            let length = if let Keys::SingleFile { length } = torrent.info.keys {
-                length
-            } else {
-                0
-            };
 
             // Tracker request for peers
-            let peers: Vec<SocketAddrV4> =
-                torrent.get_peers(&info_hash).await.context("get peers")?;
+            let peers: Vec<SocketAddr> = torrent
+                .get_peers(&info_hash, peer_id)
+                .await
+                .context("get peers")?;
             for peer in &peers {
-                println!("{}:{}", peer.ip(), peer.port());
+                println!("{}", peer);
             }
-            let peers_len = peers.len();
-            let range = rand::thread_rng().gen_range(0..peers_len);
-            let peer = peers[2];
-
-            // Handshake
-            let mut peer = tokio::net::TcpStream::connect(peer)
-                .await
-                .context("connect to peer")?;
-
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-
-            peer.write_all(&bincode::serialize(&handshake).unwrap())
-                .await?;
 
-            let mut buf = [0; 68];
-            peer.read_exact(&mut buf).await?;
-
-            let handshake: Handshake = bincode::deserialize(&buf).unwrap();
-
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {}", hex::encode(&handshake.peer_id));
-
-            /// Download piece
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-
-            // Receive Bitfield msg
-            let msg = peer
-                .next()
-                .await
-                .expect("peers always sends the first msg")
-                .context("peer msg was invalid")?;
-            // eprintln!("msg: {:?}", msg);
-            assert_eq!(msg.tag, MessageTag::Bitfield);
-
-            // Send interested msg
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: vec![],
-            })
-            .await
-            .context("send interested message")?;
-
-            // recv unchoke
-            let msg = peer
-                .next()
-                .await
-                .expect("peer next msg")
-                .context("peer msg was invalid")?;
-            // eprintln!("msg: {:?}", msg);
-            assert_eq!(msg.tag, MessageTag::Unchoke);
+            // Download from the first peer that has the piece and unchokes us
+            let handshake = Handshake::new(info_hash, peer_id);
+            let num_pieces = torrent.info.pieces.0.len();
+            let mut found = None;
+            for &addr in &peers {
+                let attempt = async {
+                    // Handshake
+                    let (mut peer, handshake) = peer::connect(addr, &handshake)
+                        .await
+                        .context("handshake with peer")?;
+
+                    // Wait for the peer to let us download
+                    let bitfield = peer::await_unchoke(&mut peer, num_pieces)
+                        .await
+                        .context("wait for unchoke")?;
+                    anyhow::ensure!(bitfield.get(piece), "peer does not have piece {}", piece);
+                    Ok((peer, handshake))
+                };
+                let attempt = tokio::time::timeout(PEER_TIMEOUT, attempt)
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+                match attempt {
+                    Ok(peer) => {
+                        found = Some(peer);
+                        break;
+                    }
+                    Err(e) => eprintln!("peer {}: {:#}", addr, e),
+                }
+            }
+            let (mut peer, handshake) =
+                found.with_context(|| format!("no peer has piece {}", piece))?;
+            println!("Peer ID: {}", hex::encode(handshake.peer_id));
 
             // Download a piece
             let piece_buf = torrent
-                .download_piece(piece, &mut peer)
+                .download_piece(piece, &mut peer, max_requests)
                 .await
                 .context("Download piece")?;
 
@@ -217,86 +295,154 @@ async fn main() -> anyhow::Result<()> {
                 .context("write out downloaded piece")?;
             println!("Piece {piece} downloaded to {}.", output.display());
         }
-        Command::Download { output, torrent } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let torrent: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+        Command::Download {
+            output,
+            torrent,
+            max_requests,
+        } => {
+            let torrent = load_torrent(&torrent, peer_id).await?;
             // eprintln!("torrent: {:?}", torrent);
 
             let info_hash = torrent.info_hash();
-            let length = if let Keys::SingleFile { length } = torrent.info.keys {
-                length
-            } else {
-                0
-            };
 
-            // Tracker request for peers
-            let peers: Vec<SocketAddrV4> =
-                torrent.get_peers(&info_hash).await.context("get peers")?;
-            for peer in &peers {
-                println!("{}:{}", peer.ip(), peer.port());
+            // The download starts the session once it knows what is left
+            let mut session = TrackerSession::new(
+                TrackerList::new(torrent.trackers()),
+                info_hash,
+                peer_id,
+                torrent.info.length(),
+            );
+
+            // Download file, telling the tracker we left whether it finishes or is interrupted
+            let downloaded = tokio::select! {
+                downloaded = torrent
+                    .download_file(&output, peer_id, &mut session, max_requests) => downloaded,
+                _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("interrupted")),
+            };
+            if let Err(e) = session.stop().await {
+                eprintln!("announce failed: {:#}", e);
             }
-            let peers_len = peers.len();
-            let range = rand::thread_rng().gen_range(0..peers_len);
-            let peer = peers[2];
-
-            // Handshake
-            let mut peer = tokio::net::TcpStream::connect(peer)
-                .await
-                .context("connect to peer")?;
-
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-
-            peer.write_all(&bincode::serialize(&handshake).unwrap())
-                .await?;
-
-            let mut buf = [0; 68];
-            peer.read_exact(&mut buf).await?;
-
-            let handshake: Handshake = bincode::deserialize(&buf).unwrap();
-
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {}", hex::encode(&handshake.peer_id));
-
-            /// Download piece
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-
-            //Receive Bitfield msg
-            let msg = peer
-                .next()
-                .await
-                .expect("peers always sends the first msg")
-                .context("peer msg was invalid")?;
-            // eprintln!("msg: {:?}", msg);
-            assert_eq!(msg.tag, MessageTag::Bitfield);
+            downloaded.context("Download file")?;
 
-            // Send interested msg
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: vec![],
-            })
-            .await
-            .context("send interested message")?;
+            println!("Downloaded test.torrent to {}.", output.display());
+        }
+        Command::Create {
+            path,
+            output,
+            announce,
+            piece_length,
+            comment,
+            created_by,
+            no_creation_date,
+            private,
+        } => {
+            let mut builder = Torrent::builder(path)
+                .created_by(created_by)
+                .private(private);
+            for tier in announce {
+                builder = builder.tracker_tier(tier.split(',').map(String::from).collect());
+            }
+            if let Some(piece_length) = piece_length {
+                builder = builder.piece_length(piece_length);
+            }
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+            if no_creation_date {
+                builder = builder.creation_date(None);
+            }
+            let torrent = builder.build().context("create torrent")?;
 
-            // recv unchoke
-            let msg = peer
-                .next()
-                .await
-                .expect("peer next msg")
-                .context("peer msg was invalid")?;
-            // eprintln!("msg: {:?}", msg);
-            assert_eq!(msg.tag, MessageTag::Unchoke);
+            std::fs::write(&output, torrent.to_bytes()).context("write torrent file")?;
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()));
+            println!("Torrent written to {}.", output.display());
+        }
+        Command::Verify { torrent, path } => {
+            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
+            let torrent = Torrent::from_bytes(&dot_torrent)?;
+            let storage = FsStorage::new(&torrent.info, &path)?;
+
+            let verification = verify::verify(&torrent.info, &storage);
+
+            println!(
+                "Complete: {}/{} pieces ({:.2}%)",
+                verification.completed(),
+                verification.pieces.len(),
+                verification.percent()
+            );
+            let bad_pieces = verification.bad_pieces();
+            if !bad_pieces.is_empty() {
+                let bad_pieces: Vec<String> = bad_pieces.iter().map(|p| p.to_string()).collect();
+                println!("Bad pieces: {}", bad_pieces.join(","));
+            }
+            println!("Bitfield: {}", hex::encode(verification.pieces.as_bytes()));
+        }
+        Command::Scrape { torrents } => {
+            // Torrents sharing a tracker are scraped with a single request.
+            let mut by_tracker: BTreeMap<String, Vec<[u8; 20]>> = BTreeMap::new();
+            let mut loaded = Vec::new();
+            for path in torrents {
+                let dot_torrent = std::fs::read(&path)
+                    .with_context(|| format!("read torrent file {}", path.display()))?;
+                let torrent = Torrent::from_bytes(&dot_torrent)?;
+                let info_hash = torrent.info_hash();
+                let tracker = torrent.trackers().into_iter().flatten().next();
+                if let Some(tracker) = &tracker {
+                    by_tracker
+                        .entry(tracker.clone())
+                        .or_default()
+                        .push(info_hash);
+                }
+                loaded.push((path, info_hash, tracker));
+            }
 
-            // Download file
-            let res = torrent
-                .download_file(&output, &mut peer)
-                .await
-                .context("Download file")?;
+            let mut results = BTreeMap::new();
+            for (url, info_hashes) in by_tracker {
+                let stats = match TrackerClient::new(&url) {
+                    Ok(mut tracker) => {
+                        tokio::time::timeout(SCRAPE_TIMEOUT, tracker.scrape(&info_hashes))
+                            .await
+                            .unwrap_or_else(|_| Err(TrackerError::Timeout.into()))
+                    }
+                    Err(e) => Err(e),
+                };
+                results.insert(url, stats);
+            }
 
-            println!("Downloaded test.torrent to {}.", output.display());
+            for (path, info_hash, tracker) in loaded {
+                let Some(tracker) = tracker else {
+                    println!("{}: no tracker", path.display());
+                    continue;
+                };
+                match &results[&tracker] {
+                    Ok(stats) => match stats.get(&info_hash) {
+                        Some(stats) => println!(
+                            "{}: {} seeders, {} leechers, {} downloads ({})",
+                            path.display(),
+                            stats.complete,
+                            stats.incomplete,
+                            stats.downloaded,
+                            tracker
+                        ),
+                        None => println!("{}: unknown to {}", path.display(), tracker),
+                    },
+                    Err(e) => println!("{}: {} failed: {:#}", path.display(), tracker, e),
+                }
+            }
         }
     }
 
     Ok(())
 }
+
+/// Reads a torrent file, or fetches the metadata from peers if `torrent` is a magnet link.
+async fn load_torrent(torrent: &str, peer_id: PeerId) -> anyhow::Result<Torrent> {
+    if torrent.starts_with("magnet:") {
+        let link: MagnetLink = torrent.parse().context("parse magnet link")?;
+        return metadata::fetch_torrent(&link, peer_id)
+            .await
+            .context("fetch metadata");
+    }
+    let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
+    Torrent::from_bytes(&dot_torrent)
+}
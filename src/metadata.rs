@@ -0,0 +1,220 @@
+//! Metadata exchange (BEP 9, `ut_metadata`), which downloads the info dictionary of a torrent
+//! from peers so that a magnet link is enough to start a download.
+//!
+//! The info dictionary is transferred in pieces of 16 KiB (the last one may be shorter). Every
+//! `ut_metadata` message starts with a bencoded dictionary:
+//! - `{'msg_type': 0, 'piece': n}` requests piece `n`
+//! - `{'msg_type': 1, 'piece': n, 'total_size': size}` followed by the piece's bytes answers it
+//! - `{'msg_type': 2, 'piece': n}` rejects the request
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use crate::{
+    bencode::{self, Value},
+    extension::{self, ExtensionHandler, ExtensionHandshake, Extensions},
+    magnet::MagnetLink,
+    peer::{self, Handshake, Message, MessageFramer, PeerId},
+    torrent::Torrent,
+    tracker::{self, TrackerList},
+};
+
+pub const METADATA_PIECE_SIZE: usize = 1 << 14;
+
+/// Refuse info dictionaries larger than this, so a peer cannot make us allocate without bound.
+const MAX_METADATA_SIZE: usize = 16 << 20;
+
+/// How long a single peer gets to deliver the whole info dictionary.
+const PEER_TIMEOUT: Duration = Duration::from_secs(20);
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+/// Resolves a magnet link into a [`Torrent`]: finds peers through the link's trackers and
+/// `x.pe` addresses, then asks them for the info dictionary until one delivers it.
+pub async fn fetch_torrent(link: &MagnetLink, peer_id: PeerId) -> anyhow::Result<Torrent> {
+    let mut peers: Vec<SocketAddr> = link.peers.iter().filter_map(|p| p.parse().ok()).collect();
+    // Trackers from a magnet link are not tiered, so each one gets a tier of its own.
+    let mut trackers = TrackerList::new(link.trackers.iter().map(|t| vec![t.clone()]).collect());
+    if !trackers.is_empty() {
+        // The size is unknown until we have the metadata, so pretend a single byte is left.
+        match tracker::get_peers(&mut trackers, &link.info_hash, peer_id, 1).await {
+            Ok(found) => peers.extend(found),
+            Err(e) => eprintln!("{:#}", e),
+        }
+    }
+    anyhow::ensure!(!peers.is_empty(), "no peers found for magnet link");
+
+    for addr in peers {
+        let raw_info =
+            match tokio::time::timeout(PEER_TIMEOUT, fetch_from(addr, link.info_hash, peer_id))
+                .await
+            {
+                Ok(Ok(raw_info)) => raw_info,
+                Ok(Err(e)) => {
+                    eprintln!("peer {}: {:#}", addr, e);
+                    continue;
+                }
+                Err(_) => {
+                    eprintln!("peer {}: timed out fetching metadata", addr);
+                    continue;
+                }
+            };
+        return Torrent::from_raw_info(raw_info, &link.trackers);
+    }
+    anyhow::bail!("no peer delivered the metadata")
+}
+
+async fn fetch_from(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+) -> anyhow::Result<Vec<u8>> {
+    let handshake = Handshake::new(info_hash, peer_id).with_extension_protocol();
+    let (mut peer, reply) = peer::connect(addr, &handshake).await?;
+    anyhow::ensure!(
+        reply.supports_extension_protocol(),
+        "peer does not support the extension protocol"
+    );
+    fetch_info(&mut peer, &info_hash).await
+}
+
+/// Downloads the info dictionary over an established connection and checks it against
+/// `info_hash`.
+pub async fn fetch_info(
+    peer: &mut Framed<TcpStream, MessageFramer>,
+    info_hash: &[u8; 20],
+) -> anyhow::Result<Vec<u8>> {
+    let mut extensions = Extensions::new();
+    extensions.register(MetadataExchange::new(*info_hash));
+    peer.send(extensions.handshake(None).to_message())
+        .await
+        .context("send extension handshake")?;
+
+    loop {
+        let msg = peer
+            .next()
+            .await
+            .context("peer closed the connection")?
+            .context("peer msg was invalid")?;
+        for reply in extensions.handle(&msg)? {
+            peer.send(reply).await.context("send metadata request")?;
+        }
+        let exchange = extensions
+            .get_mut::<MetadataExchange>()
+            .expect("ut_metadata is registered");
+        if let Some(metadata) = exchange.take_metadata() {
+            return Ok(metadata);
+        }
+    }
+}
+
+/// The `ut_metadata` extension, downloading the info dictionary of the torrent with the given
+/// info hash.
+#[derive(Debug)]
+pub struct MetadataExchange {
+    info_hash: [u8; 20],
+    metadata: Vec<u8>,
+    received: Vec<bool>,
+    // Set once all pieces arrived and the metadata matched the info hash.
+    done: bool,
+}
+
+impl MetadataExchange {
+    pub fn new(info_hash: [u8; 20]) -> Self {
+        Self {
+            info_hash,
+            metadata: Vec::new(),
+            received: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Returns the verified info dictionary once it has been downloaded completely.
+    pub fn take_metadata(&mut self) -> Option<Vec<u8>> {
+        if !self.done {
+            return None;
+        }
+        self.done = false;
+        Some(std::mem::take(&mut self.metadata))
+    }
+}
+
+impl ExtensionHandler for MetadataExchange {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn on_handshake(
+        &mut self,
+        handshake: &ExtensionHandshake,
+        id: Option<u8>,
+    ) -> anyhow::Result<Vec<Message>> {
+        let their_id = id.context("peer does not support ut_metadata")?;
+        let size = handshake
+            .metadata_size
+            .context("peer did not announce metadata_size")?;
+        anyhow::ensure!(
+            size > 0 && size <= MAX_METADATA_SIZE,
+            "invalid metadata_size {}",
+            size
+        );
+
+        let num_pieces = size.div_ceil(METADATA_PIECE_SIZE);
+        self.metadata = vec![0u8; size];
+        self.received = vec![false; num_pieces];
+        Ok((0..num_pieces)
+            .map(|piece| {
+                let request = BTreeMap::from([
+                    (b"msg_type".to_vec(), Value::Int(REQUEST)),
+                    (b"piece".to_vec(), Value::Int(piece as i64)),
+                ]);
+                extension::message(their_id, &bencode::encode(&Value::Dict(request)))
+            })
+            .collect())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Message>> {
+        let mut decoder = bencode::Decoder::new(payload);
+        let header = decoder.decode_value().context("decode metadata message")?;
+        let msg_type = header.get("msg_type").and_then(Value::as_int);
+        let piece = header
+            .get("piece")
+            .and_then(Value::as_int)
+            .and_then(|p| usize::try_from(p).ok())
+            .filter(|&p| p < self.received.len())
+            .context("metadata message for an invalid piece")?;
+        match msg_type {
+            Some(DATA) => {
+                let data = decoder.remaining();
+                let start = piece * METADATA_PIECE_SIZE;
+                let expected = METADATA_PIECE_SIZE.min(self.metadata.len() - start);
+                anyhow::ensure!(
+                    data.len() == expected,
+                    "metadata piece {} has {} bytes, expected {}",
+                    piece,
+                    data.len(),
+                    expected
+                );
+                self.metadata[start..start + expected].copy_from_slice(data);
+                self.received[piece] = true;
+            }
+            Some(REJECT) => anyhow::bail!("peer rejected metadata piece {}", piece),
+            _ => {}
+        }
+
+        if !self.received.contains(&false) {
+            let hash: [u8; 20] = Sha1::digest(&self.metadata).into();
+            anyhow::ensure!(
+                hash == self.info_hash,
+                "metadata does not match the info hash"
+            );
+            self.done = true;
+        }
+        Ok(Vec::new())
+    }
+}
@@ -1,9 +1,60 @@
+use anyhow::Context;
 use bytes::BufMut;
 use bytes::{Buf, BytesMut};
-use serde::{self, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
-use std::fmt;
+use futures_util::{SinkExt, StreamExt};
+use serde::{self, Deserialize, Serialize};
+use std::{fmt, net::SocketAddr, str::FromStr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio_util::codec::Decoder;
 use tokio_util::codec::Encoder;
+use tokio_util::codec::Framed;
+
+use crate::{
+    bitfield::Bitfield,
+    error::{Error, Result},
+};
+
+/// Client and version in the Azureus-style peer id convention: `-` + client id + version + `-`.
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-RB0100-";
+
+/// The 20 byte id we identify ourselves with to trackers and peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// [`PEER_ID_PREFIX`] followed by 12 random alphanumeric characters, so every session
+    /// gets its own id.
+    pub fn random() -> Self {
+        const CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+        let mut id = [0u8; 20];
+        id[..8].copy_from_slice(PEER_ID_PREFIX);
+        for b in &mut id[8..] {
+            *b = CHARS[rand::random::<usize>() % CHARS.len()];
+        }
+        Self(id)
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = anyhow::Error;
+
+    /// Accepts either 20 characters used as is or 40 hex digits.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = match s.len() {
+            20 => s.as_bytes().to_vec(),
+            40 => hex::decode(s).context("invalid hex peer id")?,
+            n => anyhow::bail!("peer id must be 20 characters or 40 hex digits, got {}", n),
+        };
+        Ok(Self(bytes.try_into().expect("peer id is 20 bytes")))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Handshake {
@@ -15,20 +66,106 @@ pub struct Handshake {
     pub reserved: [u8; 8],
     // sha1 infohash (20 bytes) (NOT the hexadecimal representation, which is 40 bytes long)
     pub info_hash: [u8; 20],
-    // peer id (20 bytes) (you can use 00112233445566778899 for this challenge)
+    // peer id (20 bytes), see `PeerId`
     pub peer_id: [u8; 20],
 }
 
 impl Handshake {
-    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+    pub fn new(info_hash: [u8; 20], peer_id: PeerId) -> Self {
         Self {
             length: 19,
             bittorrent: *b"BitTorrent protocol",
             reserved: [0; 8],
             info_hash,
-            peer_id,
+            peer_id: peer_id.0,
         }
     }
+
+    /// Advertises support for the extension protocol (BEP 10) by setting the 20th bit from the
+    /// right of the reserved bytes.
+    pub fn with_extension_protocol(mut self) -> Self {
+        self.reserved[5] |= 0x10;
+        self
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+}
+
+/// Connects to `addr` and exchanges handshakes, returning the connection framed for peer
+/// messages together with the handshake the peer answered with.
+pub async fn connect(
+    addr: SocketAddr,
+    handshake: &Handshake,
+) -> Result<(Framed<TcpStream, MessageFramer>, Handshake)> {
+    let mut stream = TcpStream::connect(addr).await?;
+
+    stream
+        .write_all(&bincode::serialize(handshake).expect("serialize handshake"))
+        .await?;
+
+    let mut buf = [0; 68];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::PeerDisconnected,
+            _ => Error::Io(e),
+        })?;
+    let reply: Handshake = bincode::deserialize(&buf).expect("68 bytes hold a handshake");
+
+    if reply.length != 19 || &reply.bittorrent != b"BitTorrent protocol" {
+        return Err(Error::Protocol(
+            "peer does not speak the BitTorrent protocol".to_string(),
+        ));
+    }
+    if reply.info_hash != handshake.info_hash {
+        return Err(Error::Protocol(
+            "peer answered with a different info hash".to_string(),
+        ));
+    }
+
+    Ok((Framed::new(stream, MessageFramer), reply))
+}
+
+/// Receives the next message, treating the end of the stream as a disconnect.
+pub async fn next_message(peer: &mut Framed<TcpStream, MessageFramer>) -> Result<Message> {
+    peer.next().await.ok_or(Error::PeerDisconnected)?
+}
+
+/// Declares interest and waits until the peer unchokes us. Returns the pieces of a torrent of
+/// `num_pieces` pieces the peer told us it has so far, with its bitfield or `have` messages.
+pub async fn await_unchoke(
+    peer: &mut Framed<TcpStream, MessageFramer>,
+    num_pieces: usize,
+) -> Result<Bitfield> {
+    peer.send(Message {
+        tag: MessageTag::Interested,
+        payload: vec![],
+    })
+    .await?;
+
+    let mut bitfield = Bitfield::new(num_pieces);
+    loop {
+        let msg = next_message(peer).await?;
+        match msg.tag {
+            MessageTag::Unchoke => return Ok(bitfield),
+            MessageTag::Bitfield => bitfield = Bitfield::from_bytes(&msg.payload, num_pieces)?,
+            MessageTag::Have => bitfield.set(parse_have(&msg.payload, num_pieces)?, true),
+            // Anything else can wait until we download.
+            _ => {}
+        }
+    }
+}
+
+/// The piece index of a `have` message's payload, checked against the torrent's `num_pieces`.
+pub fn parse_have(payload: &[u8], num_pieces: usize) -> Result<usize> {
+    <[u8; 4]>::try_from(payload)
+        .map(|b| u32::from_be_bytes(b) as usize)
+        .ok()
+        .filter(|&piece| piece < num_pieces)
+        .ok_or_else(|| Error::Protocol("invalid have message".to_string()))
 }
 
 /// Peer message type
@@ -44,6 +181,7 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
 }
 
 impl MessageTag {
@@ -58,6 +196,7 @@ impl MessageTag {
             6 => Some(MessageTag::Request),
             7 => Some(MessageTag::Piece),
             8 => Some(MessageTag::Cancel),
+            20 => Some(MessageTag::Extended),
             _ => None,
         }
     }
@@ -94,12 +233,18 @@ pub struct PieceResponse {
 }
 
 impl PieceResponse {
-    pub fn from_bytes(b: &[u8]) -> Self {
-        PieceResponse {
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        if b.len() < 8 {
+            return Err(Error::Protocol(format!(
+                "piece message of {} bytes is too short",
+                b.len()
+            )));
+        }
+        Ok(PieceResponse {
             index: [b[0], b[1], b[2], b[3]],
             begin: [b[4], b[5], b[6], b[7]],
             block: b[8..].to_vec(),
-        }
+        })
     }
 }
 
@@ -109,76 +254,75 @@ const MAX: usize = 1 << 16;
 
 impl Decoder for MessageFramer {
     type Item = Message;
-    type Error = std::io::Error;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 4 {
-            // Not enough data to read length marker + tag.
-            return Ok(None);
-        }
+        // Loops over the frames that carry no message for us.
+        loop {
+            if src.len() < 4 {
+                // Not enough data to read length marker + tag.
+                return Ok(None);
+            }
 
-        // Read length marker.
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
-
-        // if length == 0 {
-        //     // hearbeat msg
-        //     // discard it
-        //     src.advance(4);
-        //     // try again in case buffer has more msgs
-        //     return self.decode(src);
-        // }
-        // if src.len() < 5 {
-        //     // Not enough data to read length marker + tag.
-        //     return Ok(None);
-        // }
-
-        // Check that the length is not too large to avoid a denial of
-        // service attack where the server runs out of memory.
-        if length > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", length),
-            ));
-        }
+            // Read length marker.
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&src[..4]);
+            let length = u32::from_be_bytes(length_bytes) as usize;
 
-        if src.len() < 4 + length {
-            // The full string has not yet arrived.
-            //
-            // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            src.reserve(4 + length - src.len());
+            if length == 0 {
+                // hearbeat msg
+                // discard it
+                src.advance(4);
+                // try again in case buffer has more msgs
+                continue;
+            }
 
-            // We inform the Framed that we need more bytes to form the next
-            // frame.
-            return Ok(None);
-        }
+            // Check that the length is not too large to avoid a denial of
+            // service attack where the server runs out of memory.
+            if length > MAX {
+                return Err(Error::Protocol(format!(
+                    "Frame of length {} is too large.",
+                    length
+                )));
+            }
+
+            if src.len() < 4 + length {
+                // The full string has not yet arrived.
+                //
+                // We reserve more space in the buffer. This is not strictly
+                // necessary, but is a good idea performance-wise.
+                src.reserve(4 + length - src.len());
 
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let tag = src[4];
-        let data = src[5..4 + length].to_vec();
-        src.advance(4 + length);
+                // We inform the Framed that we need more bytes to form the next
+                // frame.
+                return Ok(None);
+            }
 
-        Ok(Some(Message {
-            tag: MessageTag::from_u8(tag).expect("valid messagetag"),
-            payload: data,
-        }))
+            // Use advance to modify src such that it no longer contains
+            // this frame.
+            let tag = src[4];
+            let data = src[5..4 + length].to_vec();
+            src.advance(4 + length);
+
+            // Messages of extensions we did not negotiate are ignored, as BEP 3 asks.
+            if let Some(tag) = MessageTag::from_u8(tag) {
+                return Ok(Some(Message { tag, payload: data }));
+            }
+        }
     }
 }
 
 impl Encoder<Message> for MessageFramer {
-    type Error = std::io::Error;
+    type Error = Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
         // Don't send a msg if it is longer than the other end will
         // accept.
         if item.payload.len() + 1 > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", item.payload.len() + 1),
-            ));
+            return Err(Error::Protocol(format!(
+                "Frame of length {} is too large.",
+                item.payload.len() + 1
+            )));
         }
 
         let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
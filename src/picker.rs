@@ -0,0 +1,246 @@
+//! Piece selection.
+//!
+//! Downloading the pieces that the fewest peers have first ("rarest first") keeps them from
+//! disappearing from the swarm and spreads the pieces we have to offer. Two exceptions apply:
+//! - pieces some blocks of which were already received are finished first, so that their
+//!   data is not held on to for long;
+//! - until we have a few pieces, they are picked at random, since a rare piece takes longer to
+//!   get and we want something to trade soon.
+use rand::seq::SliceRandom;
+
+use crate::bitfield::Bitfield;
+
+/// How many pieces are picked at random before switching to rarest first.
+pub const RANDOM_FIRST_PIECES: usize = 4;
+
+/// Keeps track of which pieces we have, which ones are being downloaded and how many peers
+/// have each of them, and decides which piece to download next.
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    // Pieces we have verified.
+    have: Bitfield,
+    // Pieces a peer is downloading right now.
+    claimed: Bitfield,
+    // Pieces that are not claimed but have some of their blocks received.
+    partial: Bitfield,
+    // How many connected peers have each piece.
+    availability: Vec<usize>,
+    random_first: usize,
+}
+
+impl PiecePicker {
+    /// A picker for a torrent of `have.len()` pieces, of which the ones set in `have` are
+    /// already downloaded.
+    pub fn new(have: Bitfield) -> Self {
+        let num_pieces = have.len();
+        Self {
+            have,
+            claimed: Bitfield::new(num_pieces),
+            partial: Bitfield::new(num_pieces),
+            availability: vec![0; num_pieces],
+            random_first: RANDOM_FIRST_PIECES,
+        }
+    }
+
+    /// Picks the first `n` pieces at random instead of the [`RANDOM_FIRST_PIECES`] default.
+    pub fn random_first(mut self, n: usize) -> Self {
+        self.random_first = n;
+        self
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.is_complete()
+    }
+
+    /// Whether a peer with the pieces in `remote` has any piece we still need.
+    pub fn wants_any(&self, remote: &Bitfield) -> bool {
+        remote.difference(&self.have).any()
+    }
+
+    /// Whether every piece we lack is being downloaded, so that there is nothing left to pick
+    /// and the remaining blocks are worth requesting from more than one peer.
+    pub fn in_endgame(&self) -> bool {
+        (0..self.have.len()).all(|i| self.have.get(i) || self.claimed.get(i))
+    }
+
+    /// Counts the pieces of a newly connected peer, or of its bitfield.
+    pub fn add_peer(&mut self, remote: &Bitfield) {
+        for piece in remote.ones() {
+            self.availability[piece] += 1;
+        }
+    }
+
+    /// Forgets the pieces of a peer that went away.
+    pub fn remove_peer(&mut self, remote: &Bitfield) {
+        for piece in remote.ones() {
+            self.availability[piece] -= 1;
+        }
+    }
+
+    /// Counts a piece a peer announced with a `have` message.
+    pub fn peer_has(&mut self, piece: usize) {
+        self.availability[piece] += 1;
+    }
+
+    /// Claims the next piece to download from a peer with the pieces in `remote`, if it has
+    /// any that we need and nobody is downloading.
+    pub fn pick(&mut self, remote: &Bitfield) -> Option<usize> {
+        let candidates: Vec<usize> = remote
+            .difference(&self.have)
+            .difference(&self.claimed)
+            .ones()
+            .collect();
+        let partial: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|&i| self.partial.get(i))
+            .collect();
+
+        let piece = if !partial.is_empty() {
+            self.rarest(&partial)
+        } else if self.have.count() < self.random_first {
+            candidates.choose(&mut rand::thread_rng()).copied()
+        } else {
+            self.rarest(&candidates)
+        }?;
+        self.claimed.set(piece, true);
+        self.partial.set(piece, false);
+        Some(piece)
+    }
+
+    /// One of the least available `pieces`, chosen at random among equals.
+    fn rarest(&self, pieces: &[usize]) -> Option<usize> {
+        let min = pieces.iter().map(|&i| self.availability[i]).min()?;
+        let rarest: Vec<usize> = pieces
+            .iter()
+            .copied()
+            .filter(|&i| self.availability[i] == min)
+            .collect();
+        rarest.choose(&mut rand::thread_rng()).copied()
+    }
+
+    /// Gives up a claimed piece, so that it can be picked again. `partial` tells whether some
+    /// of its blocks were received and kept.
+    pub fn release(&mut self, piece: usize, partial: bool) {
+        self.claimed.set(piece, false);
+        self.partial.set(piece, partial);
+    }
+
+    /// Marks a claimed piece as downloaded and verified.
+    pub fn complete(&mut self, piece: usize) {
+        self.have.set(piece, true);
+        self.claimed.set(piece, false);
+        self.partial.set(piece, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitfield(pieces: &[bool]) -> Bitfield {
+        pieces.iter().copied().collect()
+    }
+
+    /// A picker for `n` pieces, none of which we have, that picks rarest first right away.
+    fn picker(n: usize) -> PiecePicker {
+        PiecePicker::new(Bitfield::new(n)).random_first(0)
+    }
+
+    #[test]
+    fn counts_availability() {
+        let mut picker = picker(3);
+        let a = bitfield(&[true, true, false]);
+        let b = bitfield(&[true, false, false]);
+        picker.add_peer(&a);
+        picker.add_peer(&b);
+        assert_eq!(picker.availability, [2, 1, 0]);
+        picker.peer_has(2);
+        assert_eq!(picker.availability, [2, 1, 1]);
+        picker.remove_peer(&a);
+        assert_eq!(picker.availability, [1, 0, 1]);
+    }
+
+    #[test]
+    fn picks_rarest_first() {
+        let mut picker = picker(4);
+        picker.add_peer(&bitfield(&[true, true, true, true]));
+        picker.add_peer(&bitfield(&[true, false, false, true]));
+        picker.add_peer(&bitfield(&[true, false, false, false]));
+        let all = bitfield(&[true; 4]);
+
+        // Pieces 1 and 2 are tied for rarest, and either may come first.
+        let mut first = [picker.pick(&all).unwrap(), picker.pick(&all).unwrap()];
+        first.sort();
+        assert_eq!(first, [1, 2]);
+        assert_eq!(picker.pick(&all), Some(3));
+        assert_eq!(picker.pick(&all), Some(0));
+        assert_eq!(picker.pick(&all), None);
+    }
+
+    #[test]
+    fn picks_only_what_the_peer_has() {
+        let mut picker = picker(3);
+        picker.add_peer(&bitfield(&[true, true, true]));
+        picker.add_peer(&bitfield(&[true, true, false]));
+        let remote = bitfield(&[true, false, false]);
+        assert_eq!(picker.pick(&remote), Some(0));
+        assert_eq!(picker.pick(&remote), None);
+    }
+
+    #[test]
+    fn prefers_partial_pieces() {
+        let mut picker = picker(3);
+        picker.add_peer(&bitfield(&[true, true, true]));
+        picker.add_peer(&bitfield(&[false, true, true]));
+        let all = bitfield(&[true; 3]);
+
+        assert_eq!(picker.pick(&all), Some(0));
+        let mut rest = [picker.pick(&all).unwrap(), picker.pick(&all).unwrap()];
+        rest.sort();
+        assert_eq!(rest, [1, 2]);
+        // Piece 0 is released untouched and piece 2 half done: the common piece 2 comes
+        // before the rare piece 0.
+        picker.release(0, false);
+        picker.release(2, true);
+        assert_eq!(picker.pick(&all), Some(2));
+        assert_eq!(picker.pick(&all), Some(0));
+    }
+
+    #[test]
+    fn picks_at_random_until_it_has_a_few_pieces() {
+        let mut picker = PiecePicker::new(Bitfield::new(3)).random_first(1);
+        picker.add_peer(&bitfield(&[true, true, true]));
+        picker.add_peer(&bitfield(&[true, true, false]));
+        picker.add_peer(&bitfield(&[true, false, false]));
+        let all = bitfield(&[true; 3]);
+
+        let first = picker.pick(&all).unwrap();
+        picker.complete(first);
+        // With one piece in, the rarest of the others comes next.
+        let rarest = if first == 2 { 1 } else { 2 };
+        assert_eq!(picker.pick(&all), Some(rarest));
+    }
+
+    #[test]
+    fn enters_endgame_once_every_missing_piece_is_claimed() {
+        let mut picker = PiecePicker::new(bitfield(&[true, false, false])).random_first(0);
+        let all = bitfield(&[true; 3]);
+        picker.add_peer(&all);
+        assert!(!picker.in_endgame());
+        picker.pick(&all).unwrap();
+        assert!(!picker.in_endgame());
+        let last = picker.pick(&all).unwrap();
+        assert!(picker.in_endgame());
+        picker.release(last, true);
+        assert!(!picker.in_endgame());
+        picker.pick(&all).unwrap();
+        picker.complete(last);
+        assert!(picker.in_endgame());
+        assert!(!picker.is_complete());
+    }
+}
@@ -0,0 +1,151 @@
+//! Request pipelining.
+//!
+//! Waiting for every block before asking for the next one leaves the connection idle for a
+//! round trip per block. Instead we keep a window of requests outstanding with every peer,
+//! sized so that it covers a few seconds of the peer's measured download rate.
+use std::time::{Duration, Instant};
+
+use crate::torrent::BLOCK_MAX;
+
+/// The default cap on outstanding requests per peer, which is also what clients assume for
+/// peers that do not announce a `reqq`.
+pub const DEFAULT_MAX_REQUESTS: usize = 250;
+
+/// How many requests a new peer starts with, before we know its rate.
+const INITIAL_SIZE: usize = 4;
+
+/// A peer always gets this many requests, however slow it is.
+const MIN_SIZE: usize = 2;
+
+/// How much of a peer's download rate the outstanding requests should cover.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+
+/// How often the window is resized from the measured rate.
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many requests to keep outstanding with one peer.
+#[derive(Debug, Clone)]
+pub struct RequestWindow {
+    size: usize,
+    max: usize,
+    // Bytes received since `since`.
+    received: usize,
+    since: Instant,
+}
+
+impl RequestWindow {
+    /// A window that never grows beyond `max` requests.
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            size: INITIAL_SIZE.min(max),
+            max,
+            received: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// The number of requests that may be outstanding right now.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Caps the window at the number of outstanding requests the peer says it can queue.
+    pub fn limit(&mut self, reqq: usize) {
+        self.max = self.max.min(reqq).max(1);
+        self.size = self.size.min(self.max);
+    }
+
+    /// Starts measuring the rate afresh, for when the peer was not sending for reasons of its
+    /// own, such as having choked us.
+    pub fn restart(&mut self) {
+        self.received = 0;
+        self.since = Instant::now();
+    }
+
+    /// Accounts for a received block, resizing the window once enough time has passed to
+    /// measure the rate.
+    pub fn received(&mut self, bytes: usize) {
+        self.received += bytes;
+        let elapsed = self.since.elapsed();
+        if elapsed < RATE_INTERVAL {
+            return;
+        }
+        let rate = self.received as f64 / elapsed.as_secs_f64();
+        let wanted = (rate * QUEUE_TIME.as_secs_f64() / BLOCK_MAX as f64).ceil() as usize;
+        self.size = wanted.max(MIN_SIZE).min(self.max);
+        self.restart();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = BLOCK_MAX as usize;
+
+    /// Feeds `blocks` full blocks to `window` as if they arrived over `secs` seconds.
+    fn receive_over(window: &mut RequestWindow, blocks: usize, secs: u64) {
+        window.since = Instant::now() - Duration::from_secs(secs);
+        window.received(blocks * BLOCK);
+    }
+
+    #[test]
+    fn starts_small() {
+        assert_eq!(
+            RequestWindow::new(DEFAULT_MAX_REQUESTS).size(),
+            INITIAL_SIZE
+        );
+        assert_eq!(RequestWindow::new(2).size(), 2);
+        // A window always allows at least one request.
+        assert_eq!(RequestWindow::new(0).size(), 1);
+    }
+
+    #[test]
+    fn follows_the_measured_rate() {
+        let mut window = RequestWindow::new(DEFAULT_MAX_REQUESTS);
+        // 50 blocks a second, for three seconds' worth of requests.
+        receive_over(&mut window, 100, 2);
+        assert_eq!(window.size(), 150);
+        receive_over(&mut window, 10, 2);
+        assert_eq!(window.size(), 15);
+    }
+
+    #[test]
+    fn waits_for_a_full_interval_before_resizing() {
+        let mut window = RequestWindow::new(DEFAULT_MAX_REQUESTS);
+        window.received(100 * BLOCK);
+        assert_eq!(window.size(), INITIAL_SIZE);
+        // What arrived before the restart does not count.
+        window.restart();
+        receive_over(&mut window, 10, 1);
+        assert_eq!(window.size(), 30);
+    }
+
+    #[test]
+    fn stays_between_the_minimum_and_the_maximum() {
+        let mut window = RequestWindow::new(20);
+        receive_over(&mut window, 100, 1);
+        assert_eq!(window.size(), 20);
+        receive_over(&mut window, 0, 1);
+        assert_eq!(window.size(), MIN_SIZE);
+    }
+
+    #[test]
+    fn limits_to_reqq() {
+        let mut window = RequestWindow::new(DEFAULT_MAX_REQUESTS);
+        window.limit(2);
+        assert_eq!(window.size(), 2);
+        receive_over(&mut window, 100, 1);
+        assert_eq!(window.size(), 2);
+
+        // A larger reqq does not raise our own maximum, and a reqq of 0 still leaves one
+        // request.
+        let mut window = RequestWindow::new(10);
+        window.limit(500);
+        receive_over(&mut window, 100, 1);
+        assert_eq!(window.size(), 10);
+        window.limit(0);
+        assert_eq!(window.size(), 1);
+    }
+}
@@ -0,0 +1,276 @@
+//! Fast-resume state, so an interrupted download can pick up where it left off.
+//!
+//! The state is a bencoded dictionary stored next to the download with the following keys:
+//! - `info hash`: the 20-byte info hash of the torrent the state belongs to
+//! - `piece count`: the number of pieces in the torrent
+//! - `pieces`: a bitfield of the pieces that have been verified, high bit of the first byte first
+//! - `files`: a list of dictionaries with the `length` and `mtime` (nanoseconds since the Unix
+//!   epoch) of every file, as they were when the state was saved
+//!
+//! Pieces are only trusted if the files they live in still have the recorded size and
+//! modification time; pieces in files that changed are hashed again.
+use anyhow::Context;
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    bencode::{self, Value},
+    bitfield::Bitfield,
+    storage::FsStorage,
+    torrent::{Info, Keys},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    // Which pieces have been downloaded and verified.
+    pub pieces: Bitfield,
+    pub files: Vec<FileState>,
+}
+
+/// Size and modification time of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileState {
+    pub length: u64,
+    pub mtime: i64,
+}
+
+impl FileState {
+    /// Reads the current state of the file at `path`, or `None` if it does not exist.
+    pub fn of(path: &Path) -> io::Result<Option<Self>> {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mtime = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_nanos() as i64,
+            Err(e) => -(e.duration().as_nanos() as i64),
+        };
+        Ok(Some(Self {
+            length: metadata.len(),
+            mtime,
+        }))
+    }
+}
+
+impl ResumeData {
+    /// Where the state for a download written to `output` is kept: `<output>.resume` for a
+    /// single file, `<output>/<name>.resume` next to the directory of a multi-file torrent.
+    pub fn path(info: &Info, output: &Path) -> PathBuf {
+        let mut path = match info.keys {
+            Keys::SingleFile { .. } => output.as_os_str().to_owned(),
+            Keys::MultiFile { .. } => output.join(&info.name).into_os_string(),
+        };
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    /// Captures the current state of `storage`'s files along with the verified pieces.
+    pub fn capture(info_hash: [u8; 20], pieces: Bitfield, storage: &FsStorage) -> io::Result<Self> {
+        let files = storage
+            .paths()
+            .iter()
+            .map(|path| {
+                Ok(FileState::of(path)?.unwrap_or(FileState {
+                    length: 0,
+                    mtime: 0,
+                }))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            info_hash,
+            pieces,
+            files,
+        })
+    }
+
+    /// Loads the state at `path`, returning `None` if there is no such file.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("read resume file"),
+        };
+        let value = bencode::decode(&bytes).context("decode resume file")?;
+        Self::from_value(&value)
+            .context("malformed resume file")
+            .map(Some)
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let info_hash = value.get("info hash")?.as_bytes()?.try_into().ok()?;
+        let count = usize::try_from(value.get("piece count")?.as_int()?).ok()?;
+        let pieces = Bitfield::from_bytes(value.get("pieces")?.as_bytes()?, count).ok()?;
+        let files = value
+            .get("files")?
+            .as_list()?
+            .iter()
+            .map(|f| {
+                Some(FileState {
+                    length: u64::try_from(f.get("length")?.as_int()?).ok()?,
+                    mtime: f.get("mtime")?.as_int()?,
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            info_hash,
+            pieces,
+            files,
+        })
+    }
+
+    fn to_value(&self) -> Value {
+        let files = self
+            .files
+            .iter()
+            .map(|f| {
+                Value::Dict(BTreeMap::from([
+                    (b"length".to_vec(), Value::Int(f.length as i64)),
+                    (b"mtime".to_vec(), Value::Int(f.mtime)),
+                ]))
+            })
+            .collect::<Vec<_>>();
+        Value::Dict(BTreeMap::from([
+            (b"info hash".to_vec(), Value::from(&self.info_hash[..])),
+            (
+                b"piece count".to_vec(),
+                Value::Int(self.pieces.len() as i64),
+            ),
+            (b"pieces".to_vec(), Value::from(self.pieces.as_bytes())),
+            (b"files".to_vec(), Value::List(files)),
+        ]))
+    }
+
+    /// Writes the state to `path`, replacing any previous state atomically.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, bencode::encode(&self.to_value()))?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Indexes of the files whose size or modification time differ from `current`.
+    pub fn changed_files(&self, current: &ResumeData) -> Vec<usize> {
+        (0..current.files.len())
+            .filter(|&i| self.files.get(i) != current.files.get(i))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::torrent::Torrent;
+
+    fn state(pieces: &[bool], files: &[(u64, i64)]) -> ResumeData {
+        ResumeData {
+            info_hash: [7; 20],
+            pieces: pieces.iter().copied().collect(),
+            files: files
+                .iter()
+                .map(|&(length, mtime)| FileState { length, mtime })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("download.resume");
+        assert_eq!(ResumeData::load(&path).unwrap(), None);
+
+        let saved = state(
+            &[true, false, true, true, false, false, false, false, true],
+            &[(20000, 1_700_000_000_123_456_789), (0, -5)],
+        );
+        saved.save(&path).unwrap();
+        assert_eq!(ResumeData::load(&path).unwrap(), Some(saved.clone()));
+        assert_eq!(ResumeData::from_value(&saved.to_value()), Some(saved));
+    }
+
+    #[test]
+    fn rejects_malformed_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("download.resume");
+        let mut value = state(&[true; 9], &[(1, 1)]).to_value();
+        // A bitfield that does not fit the piece count.
+        if let Value::Dict(dict) = &mut value {
+            dict.insert(b"piece count".to_vec(), Value::Int(20));
+        }
+        std::fs::write(&path, bencode::encode(&value)).unwrap();
+        assert!(ResumeData::load(&path).is_err());
+        std::fs::write(&path, b"not bencode").unwrap();
+        assert!(ResumeData::load(&path).is_err());
+    }
+
+    #[test]
+    fn finds_changed_files() {
+        let saved = state(&[true], &[(1, 10), (2, 20), (3, 30)]);
+        let current = state(&[true], &[(1, 10), (2, 21), (4, 30), (5, 50)]);
+        assert_eq!(saved.changed_files(&current), [1, 2, 3]);
+        assert_eq!(saved.changed_files(&saved), Vec::<usize>::new());
+    }
+
+    /// A torrent of two files, `a` of 20000 and `b` of 30000 bytes, in four pieces of 16 KiB:
+    /// piece 0 lies in `a`, piece 1 in both and pieces 2 and 3 in `b`.
+    fn torrent(dir: &Path) -> (Torrent, FsStorage) {
+        let root = dir.join("t");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("a"), vec![1; 20000]).unwrap();
+        std::fs::write(root.join("b"), vec![2; 30000]).unwrap();
+        let torrent = Torrent::builder(&root).piece_length(16384).build().unwrap();
+        let storage = FsStorage::new(&torrent.info, dir).unwrap();
+        (torrent, storage)
+    }
+
+    #[test]
+    fn hashes_the_pieces_of_changed_files_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, storage) = torrent(dir.path());
+        let path = ResumeData::path(&torrent.info, dir.path());
+        let all = Bitfield::from_bytes(&[0xf0], 4).unwrap();
+
+        // Unchanged files are trusted as saved, even for a piece that was not verified.
+        let mut saved = all.clone();
+        saved.set(0, false);
+        ResumeData::capture(torrent.info_hash(), saved.clone(), &storage)
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        assert_eq!(torrent.resume_pieces(&storage, &path).unwrap(), saved);
+
+        // Piece 3 of `b` goes bad: the pieces of `b` are checked again, piece 0 is not.
+        let b = &storage.paths()[1];
+        let mut data = std::fs::read(b).unwrap();
+        data[29999] = 0;
+        std::fs::write(b, data).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(b)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let have = torrent.resume_pieces(&storage, &path).unwrap();
+        assert_eq!(have.iter().collect::<Vec<_>>(), [false, true, true, false]);
+    }
+
+    #[test]
+    fn ignores_state_of_another_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, storage) = torrent(dir.path());
+        let path = ResumeData::path(&torrent.info, dir.path());
+        let all = Bitfield::from_bytes(&[0xf0], 4).unwrap();
+        ResumeData::capture([0; 20], all, &storage)
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        assert!(!torrent.resume_pieces(&storage, &path).unwrap().any());
+    }
+}
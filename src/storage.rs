@@ -0,0 +1,322 @@
+//! Where downloaded data lives.
+//!
+//! Peers address data by piece index and offset within the piece, while on disk a torrent is a
+//! list of files. For the purposes of piece addressing the files are treated as one contiguous
+//! stream in the order they appear in the info dictionary, so a single block may span several
+//! files. A [`Storage`] backend hides that translation from the download code.
+use std::{
+    fs::OpenOptions,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::torrent::{Info, Keys};
+
+/// Random-access storage for the pieces of a torrent.
+///
+/// Blocks may be written in any order and rewritten any number of times.
+pub trait Storage: Send + Sync {
+    /// Writes `data` at `offset` bytes into piece `piece`.
+    fn write_block(&self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()>;
+
+    /// Reads `len` bytes starting `offset` bytes into piece `piece`.
+    fn read_block(&self, piece: usize, offset: usize, len: usize) -> io::Result<Vec<u8>>;
+}
+
+/// Maps positions in the piece stream onto the files making up a torrent.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    piece_length: usize,
+    // (offset in the stream, length) of every file.
+    files: Vec<(usize, usize)>,
+    total: usize,
+}
+
+/// A contiguous part of a block that falls within a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,
+    pub file_offset: usize,
+    // The part of the block that belongs to this file.
+    pub block_range: Range<usize>,
+}
+
+impl Layout {
+    pub fn new(info: &Info) -> Self {
+        let files = match &info.keys {
+            Keys::SingleFile { length } => vec![(0, *length)],
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|f| {
+                        let entry = (offset, f.length);
+                        offset += f.length;
+                        entry
+                    })
+                    .collect()
+            }
+        };
+        Self {
+            piece_length: info.piece_length,
+            files,
+            total: info.length(),
+        }
+    }
+
+    /// Position of the block of `len` bytes at `offset` into piece `piece` within the
+    /// concatenation of all files.
+    pub fn stream_range(
+        &self,
+        piece: usize,
+        offset: usize,
+        len: usize,
+    ) -> io::Result<Range<usize>> {
+        piece
+            .checked_mul(self.piece_length)
+            .and_then(|p| p.checked_add(offset))
+            .and_then(|start| Some(start..start.checked_add(len)?))
+            .filter(|range| range.end <= self.total)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "block at piece {} offset {} length {} is out of range",
+                        piece, offset, len
+                    ),
+                )
+            })
+    }
+
+    /// Splits the block of `len` bytes at `offset` into piece `piece` into per-file spans.
+    pub fn spans(&self, piece: usize, offset: usize, len: usize) -> io::Result<Vec<Span>> {
+        let Range { start, end } = self.stream_range(piece, offset, len)?;
+
+        Ok(self
+            .files
+            .iter()
+            .enumerate()
+            .filter_map(|(file, &(file_start, file_len))| {
+                let from = start.max(file_start);
+                let to = end.min(file_start + file_len);
+                (from < to).then(|| Span {
+                    file,
+                    file_offset: from - file_start,
+                    block_range: from - start..to - start,
+                })
+            })
+            .collect())
+    }
+}
+
+/// Stores a torrent in the filesystem using its own directory layout.
+pub struct FsStorage {
+    layout: Layout,
+    paths: Vec<PathBuf>,
+}
+
+impl FsStorage {
+    /// A single-file torrent is stored at `output`, a multi-file torrent in a directory tree
+    /// rooted at `output/<name>`.
+    pub fn new(info: &Info, output: &Path) -> anyhow::Result<Self> {
+        let paths = match info.keys {
+            Keys::SingleFile { .. } => vec![output.to_path_buf()],
+            Keys::MultiFile { .. } => info.files()?.iter().map(|f| output.join(&f.path)).collect(),
+        };
+        Ok(Self {
+            layout: Layout::new(info),
+            paths,
+        })
+    }
+
+    /// Paths of the files, in torrent order.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Creates every file (and the directories leading to it) that does not exist yet, so that
+    /// empty files are present even though no block is ever written to them.
+    pub fn create_files(&self) -> io::Result<()> {
+        for path in &self.paths {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for FsStorage {
+    fn write_block(&self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()> {
+        for span in self.layout.spans(piece, offset, data.len())? {
+            let path = &self.paths[span.file];
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            file.seek(SeekFrom::Start(span.file_offset as u64))?;
+            file.write_all(&data[span.block_range])?;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, piece: usize, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        for span in self.layout.spans(piece, offset, len)? {
+            let mut file = std::fs::File::open(&self.paths[span.file])?;
+            file.seek(SeekFrom::Start(span.file_offset as u64))?;
+            file.read_exact(&mut buf[span.block_range])?;
+        }
+        Ok(buf)
+    }
+}
+
+/// Keeps the whole torrent in memory, which is handy for tests and small payloads.
+pub struct MemoryStorage {
+    layout: Layout,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &Info) -> Self {
+        Self {
+            layout: Layout::new(info),
+            data: Mutex::new(vec![0; info.length()]),
+        }
+    }
+
+    /// Returns a copy of all data, i.e. the concatenation of the torrent's files.
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write_block(&self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()> {
+        let range = self.layout.stream_range(piece, offset, data.len())?;
+        self.data.lock().unwrap()[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_block(&self, piece: usize, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        let range = self.layout.stream_range(piece, offset, len)?;
+        Ok(self.data.lock().unwrap()[range].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{hashes::Hashes, File};
+
+    /// Files of 5, 0, 3 and 8 bytes in pieces of 4 bytes, so that piece 1 starts in the first
+    /// file and ends in the third, skipping the empty one.
+    fn info() -> Info {
+        let files = [("a", 5), ("empty", 0), ("b", 3), ("c", 8)]
+            .into_iter()
+            .map(|(name, length)| File {
+                length,
+                path: vec![name.to_string()],
+            })
+            .collect();
+        Info {
+            name: "test".to_string(),
+            piece_length: 4,
+            pieces: Hashes(vec![[0; 20]; 4]),
+            keys: Keys::MultiFile { files },
+            private: None,
+        }
+    }
+
+    fn span(file: usize, file_offset: usize, block_range: Range<usize>) -> Span {
+        Span {
+            file,
+            file_offset,
+            block_range,
+        }
+    }
+
+    #[test]
+    fn splits_blocks_at_file_boundaries() {
+        let layout = Layout::new(&info());
+        assert_eq!(layout.spans(0, 1, 2).unwrap(), [span(0, 1, 0..2)]);
+        assert_eq!(
+            layout.spans(1, 0, 4).unwrap(),
+            [span(0, 4, 0..1), span(2, 0, 1..4)]
+        );
+        assert_eq!(
+            layout.spans(0, 0, 16).unwrap(),
+            [span(0, 0, 0..5), span(2, 0, 5..8), span(3, 0, 8..16)]
+        );
+        assert_eq!(layout.spans(3, 2, 2).unwrap(), [span(3, 6, 0..2)]);
+    }
+
+    #[test]
+    fn skips_zero_length_files() {
+        let mut info = info();
+        if let Keys::MultiFile { files } = &mut info.keys {
+            files.push(File {
+                length: 0,
+                path: vec!["trailing".to_string()],
+            });
+        }
+        let layout = Layout::new(&info);
+        let spans = layout.spans(0, 0, 16).unwrap();
+        assert!(spans.iter().all(|s| s.file != 1 && s.file != 4));
+        assert_eq!(spans.len(), 3);
+    }
+
+    #[test]
+    fn rejects_out_of_range_blocks() {
+        let layout = Layout::new(&info());
+        for (piece, offset, len) in [(3, 2, 3), (4, 0, 1), (0, 0, 17), (usize::MAX, 0, 1)] {
+            let error = layout.spans(piece, offset, len).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(layout.stream_range(3, 0, 4).unwrap(), 12..16);
+    }
+
+    #[test]
+    fn memory_storage_round_trip() {
+        let storage = MemoryStorage::new(&info());
+        storage.write_block(1, 0, b"wxyz").unwrap();
+        storage.write_block(0, 2, b"ab").unwrap();
+        storage.write_block(3, 0, b"1234").unwrap();
+        assert_eq!(storage.read_block(1, 0, 4).unwrap(), b"wxyz");
+        assert_eq!(storage.read_block(0, 3, 2).unwrap(), b"bw");
+        assert_eq!(storage.contents(), b"\0\0abwxyz\0\0\0\x001234");
+        assert!(storage.read_block(3, 2, 3).is_err());
+        assert!(storage.write_block(3, 2, b"xyz").is_err());
+    }
+
+    #[test]
+    fn fs_storage_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(&info(), dir.path()).unwrap();
+        storage.create_files().unwrap();
+        let data: Vec<u8> = (0..16).collect();
+        for piece in 0..4 {
+            storage
+                .write_block(piece, 0, &data[piece * 4..piece * 4 + 4])
+                .unwrap();
+        }
+        assert_eq!(storage.read_block(1, 0, 4).unwrap(), &data[4..8]);
+        let files: Vec<Vec<u8>> = storage
+            .paths()
+            .iter()
+            .map(|path| std::fs::read(path).unwrap())
+            .collect();
+        assert_eq!(files, [&data[..5], &[], &data[5..8], &data[8..]]);
+    }
+}
@@ -1,19 +1,28 @@
 use anyhow::Context;
-use clap::{Parser, Subcommand};
-use futures_util::{SinkExt, StreamExt};
+use futures_util::SinkExt;
 use hashes::Hashes;
 use serde::{self, Deserialize, Serialize};
-use serde_json;
 use sha1::{Digest, Sha1};
-use std::{net::SocketAddrV4, path::PathBuf};
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 use tokio::net::TcpStream;
 use tokio_util::codec::Framed;
 
 use crate::{
-    peer::{Message, MessageFramer, MessageTag, PieceResponse, Request},
-    tracker::{urlencode, TrackerRequest, TrackerResponse},
+    bencode::{self, Value},
+    bitfield::Bitfield,
+    builder::TorrentBuilder,
+    engine,
+    error::Error,
+    peer::{self, Message, MessageFramer, MessageTag, PeerId, PieceResponse, Request},
+    pipeline::RequestWindow,
+    resume::ResumeData,
+    storage::{FsStorage, Storage},
+    tracker::{self, TrackerList, TrackerSession},
 };
 
 pub const BLOCK_MAX: u32 = 1 << 14;
@@ -22,164 +31,266 @@ pub const BLOCK_MAX: u32 = 1 << 14;
 #[derive(Debug, Clone, Deserialize)]
 pub struct Torrent {
     // The URL of the tracker.
+    #[serde(default)]
     pub announce: String,
+
+    // (optional) Tiers of announce URLs, a list of lists of strings (BEP 12).
+    #[serde(rename = "announce-list", default)]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    // (optional) Free-form textual comments of the author.
+    #[serde(default)]
+    pub comment: Option<String>,
+
+    // (optional) Name and version of the program used to create the .torrent.
+    #[serde(rename = "created by", default)]
+    pub created_by: Option<String>,
+
+    // (optional) The creation time of the torrent, in standard UNIX epoch format.
+    #[serde(rename = "creation date", default)]
+    pub creation_date: Option<i64>,
+
     // This maps to a dictionary, with keys described below.
     pub info: Info,
+
+    // The exact bytes of the info dictionary as they appeared in the metainfo file.
+    #[serde(skip)]
+    pub(crate) raw_info: Vec<u8>,
 }
 
 impl Torrent {
-    pub fn info_hash(&self) -> [u8; 20] {
-        let info_bencode = serde_bencode::to_bytes(&self.info)
-            .context("serialize info")
-            .unwrap();
+    /// Starts building a torrent for the file or directory at `path`.
+    pub fn builder(path: impl Into<PathBuf>) -> TorrentBuilder {
+        TorrentBuilder::new(path.into())
+    }
+
+    /// Encodes the torrent as a metainfo file, with the info dictionary copied verbatim so the
+    /// info hash is preserved.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        if !self.announce.is_empty() {
+            dict.insert(b"announce".to_vec(), Value::from(self.announce.as_str()));
+        }
+        if let Some(announce_list) = &self.announce_list {
+            let tiers = announce_list
+                .iter()
+                .map(|tier| Value::List(tier.iter().map(|t| Value::from(t.as_str())).collect()))
+                .collect();
+            dict.insert(b"announce-list".to_vec(), Value::List(tiers));
+        }
+        if let Some(comment) = &self.comment {
+            dict.insert(b"comment".to_vec(), Value::from(comment.as_str()));
+        }
+        if let Some(created_by) = &self.created_by {
+            dict.insert(b"created by".to_vec(), Value::from(created_by.as_str()));
+        }
+        if let Some(creation_date) = self.creation_date {
+            dict.insert(b"creation date".to_vec(), Value::Int(creation_date));
+        }
+
+        // "info" sorts after every other key, so it can be appended to the encoded dictionary
+        // right before its closing "e".
+        let mut out = bencode::encode(&Value::Dict(dict));
+        out.pop();
+        out.extend_from_slice(b"4:info");
+        if self.raw_info.is_empty() {
+            out.extend(serde_bencode::to_bytes(&self.info).expect("serialize info"));
+        } else {
+            out.extend_from_slice(&self.raw_info);
+        }
+        out.push(b'e');
+        out
+    }
+
+    /// Builds a torrent from a bencoded info dictionary obtained from peers (see
+    /// [`crate::metadata`]), with every tracker in a tier of its own.
+    pub fn from_raw_info(raw_info: Vec<u8>, trackers: &[String]) -> anyhow::Result<Self> {
+        let info: Info = serde_bencode::from_bytes(&raw_info).context("parse info dictionary")?;
+        Ok(Torrent {
+            announce: trackers.first().cloned().unwrap_or_default(),
+            announce_list: (trackers.len() > 1)
+                .then(|| trackers.iter().map(|t| vec![t.clone()]).collect()),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info,
+            raw_info,
+        })
+    }
+
+    /// Parses a metainfo file, keeping the original encoding of the info dictionary around.
+    pub fn from_bytes(dot_torrent: &[u8]) -> anyhow::Result<Self> {
+        let mut torrent: Torrent =
+            serde_bencode::from_bytes(dot_torrent).context("parse torrent file")?;
+        let span = bencode::find_value_span(dot_torrent, b"info")
+            .context("locate info dictionary")?
+            .context("torrent file has no info dictionary")?;
+        torrent.raw_info = dot_torrent[span].to_vec();
+        Ok(torrent)
+    }
 
+    /// The bencoded info dictionary, byte for byte as the torrent was loaded.
+    ///
+    /// Keys that `Info` does not model (`private`, `source`, ...) are preserved here, so this is
+    /// what must be hashed or served to peers asking for the metadata.
+    pub fn raw_info(&self) -> &[u8] {
+        &self.raw_info
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
         let mut hasher = Sha1::new();
-        hasher.update(info_bencode);
+        if self.raw_info.is_empty() {
+            let info_bencode = serde_bencode::to_bytes(&self.info)
+                .context("serialize info")
+                .unwrap();
+            hasher.update(info_bencode);
+        } else {
+            hasher.update(&self.raw_info);
+        }
         let info_hash = hasher.finalize();
         info_hash.into()
     }
 
-    pub async fn get_peers(&self, info_hash: &[u8; 20]) -> anyhow::Result<Vec<SocketAddrV4>> {
-        let length = if let Keys::SingleFile { length } = self.info.keys {
-            length
-        } else {
-            0
-        };
-
-        let request = TrackerRequest {
-            peer_id: String::from("00112233445566778899"),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: length,
-            compact: 1,
-        };
+    /// The tiers of tracker URLs: `announce-list` if the torrent has one, `announce` otherwise.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => tiers
+                .iter()
+                .filter(|tier| !tier.is_empty())
+                .cloned()
+                .collect(),
+            _ if self.announce.is_empty() => Vec::new(),
+            _ => vec![vec![self.announce.clone()]],
+        }
+    }
 
-        let url_params = serde_urlencoded::to_string(&request).context("Request to URL params")?;
-        let tracker_url = format!(
-            "{}?{}&info_hash={}",
-            self.announce,
-            url_params,
-            urlencode(&info_hash).expect("encode info hash")
-        );
-
-        let response = reqwest::get(tracker_url).await?;
-        let response = response.bytes().await?;
-        let tracker_response: TrackerResponse =
-            serde_bencode::from_bytes(&response).context("deserialize response")?;
-        Ok(tracker_response.peers.0)
+    pub async fn get_peers(
+        &self,
+        info_hash: &[u8; 20],
+        peer_id: PeerId,
+    ) -> crate::Result<Vec<SocketAddr>> {
+        let mut trackers = TrackerList::new(self.trackers());
+        tracker::get_peers(&mut trackers, info_hash, peer_id, self.info.length()).await
     }
 
     pub async fn download_piece(
         &self,
         piece_index: usize,
         peer: &mut Framed<TcpStream, MessageFramer>,
-    ) -> anyhow::Result<Vec<u8>> {
-        let length = if let Keys::SingleFile { length } = self.info.keys {
-            length
-        } else {
-            0
-        };
-
-        let piece_length = if piece_index < self.info.pieces.0.len() - 1 {
-            self.info.piece_length as u32
-        } else {
-            let pl = self.info.piece_length;
-            let file_len = length;
-            let rem = file_len % pl;
-            if rem == 0 {
-                pl as u32
-            } else {
-                rem as u32
-            }
-        };
+        max_requests: usize,
+    ) -> crate::Result<Vec<u8>> {
+        let piece_length = self.info.piece_len(piece_index);
         let piece_hash = self.info.pieces.0[piece_index];
-        let mut piece_buf: Vec<u8> = Vec::with_capacity(piece_length as usize);
-
-        let mut start: u32 = 0;
-        // eprintln!(
-        //     "piece_length: {} num : {}",
-        //     piece_length,
-        //     f64::ceil(piece_length as f64 / BLOCK_MAX as f64)
-        // );
-        while start < piece_length {
-            let l = if piece_length - start >= BLOCK_MAX {
-                BLOCK_MAX
-            } else {
-                piece_length - start
+        let mut piece_buf = vec![0u8; piece_length];
+
+        // Requests go out ahead of the replies, so the blocks may arrive in any order.
+        let mut window = RequestWindow::new(max_requests);
+        // Offsets of the blocks asked for and not received yet.
+        let mut pending = BTreeSet::new();
+        let mut next: usize = 0;
+        let mut received: usize = 0;
+        while received < piece_length {
+            while next < piece_length && pending.len() < window.size() {
+                let l = (piece_length - next).min(BLOCK_MAX as usize);
+                let req = Request::new(piece_index as u32, next as u32, l as u32);
+
+                let req_bincode = bincode::serialize(&req).expect("serialize request");
+
+                // Queue request msg
+                peer.feed(Message {
+                    tag: MessageTag::Request,
+                    payload: req_bincode,
+                })
+                .await?;
+                pending.insert(next);
+                next += l;
+            }
+            peer.flush().await?;
+
+            // Recv piece msg, skipping the messages that do not concern this request
+            let piece_msg = loop {
+                let msg = peer::next_message(peer).await?;
+                match msg.tag {
+                    MessageTag::Piece => break msg,
+                    MessageTag::Choke => {
+                        return Err(Error::UnexpectedMessage {
+                            expected: MessageTag::Piece,
+                            actual: msg.tag,
+                        })
+                    }
+                    _ => {}
+                }
             };
-            let req = Request::new(piece_index as u32, start, l as u32);
-            // eprintln!("req: {} {} {}", piece, start, l as u32);
-
-            let req_bincode = bincode::serialize(&req).unwrap();
-
-            // Send request msg
-            peer.send(Message {
-                tag: MessageTag::Request,
-                payload: req_bincode,
-            })
-            .await
-            .context("send request msg")?;
-
-            // Recv piece msg
-            let piece_msg = peer
-                .next()
-                .await
-                .expect("peer next msg")
-                .context("peer msg was invalid")?;
-            // eprintln!("piece_msg: {:?}", piece_msg);
-            assert_eq!(piece_msg.tag, MessageTag::Piece);
-
-            let piece_response: PieceResponse = PieceResponse::from_bytes(&piece_msg.payload);
-            // eprintln!(
-            //     "p resp: {} {} {}",
-            //     u32::from_be_bytes(piece_response.index),
-            //     u32::from_be_bytes(piece_response.begin),
-            //     piece_response.block.len()
-            // );
-            assert_eq!(u32::from_be_bytes(piece_response.index), piece_index as u32);
-            assert_eq!(u32::from_be_bytes(piece_response.begin), start);
-
-            // let mut block = piece_response.block;
-            // block.extend(piece_buf);
-            // piece_buf = block;
-            piece_buf.extend(piece_response.block);
-
-            start += BLOCK_MAX;
-        }
 
-        // piece_buf.reverse();
-
-        assert_eq!(piece_buf.len(), piece_length as usize);
+            let piece_response = PieceResponse::from_bytes(&piece_msg.payload)?;
+            let index = u32::from_be_bytes(piece_response.index) as usize;
+            let begin = u32::from_be_bytes(piece_response.begin) as usize;
+            let l = piece_response.block.len();
+            if index != piece_index
+                || !pending.remove(&begin)
+                || l != (piece_length - begin).min(BLOCK_MAX as usize)
+            {
+                return Err(Error::Protocol(format!(
+                    "got {} bytes at {} of piece {}, which we did not ask for",
+                    l, begin, index
+                )));
+            }
+            piece_buf[begin..begin + l].copy_from_slice(&piece_response.block);
+            received += l;
+            window.received(l);
+        }
 
         // calc hash
         let mut hasher = Sha1::new();
         hasher.update(&piece_buf);
         let info_hash: [u8; 20] = hasher.finalize().into();
-        assert_eq!(info_hash, piece_hash);
+        if info_hash != piece_hash {
+            return Err(Error::HashMismatch(piece_index));
+        }
 
         Ok(piece_buf)
     }
 
+    /// Downloads every piece from the swarm and writes it out, see [`engine::download`].
     pub async fn download_file(
         &self,
-        file_path: &PathBuf,
-        peer: &mut Framed<TcpStream, MessageFramer>,
+        output: &Path,
+        peer_id: PeerId,
+        session: &mut TrackerSession,
+        max_requests: usize,
     ) -> anyhow::Result<()> {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(file_path)
-            .await?;
+        engine::download(self, output, peer_id, session, max_requests).await
+    }
 
-        for piece_index in 0..self.info.pieces.0.len() {
-            let piece_buf = self.download_piece(piece_index, peer).await?;
+    /// Works out which pieces are already on disk from the resume state at `path`. Pieces in
+    /// files that changed since the state was saved are hashed again.
+    pub(crate) fn resume_pieces(
+        &self,
+        storage: &FsStorage,
+        path: &Path,
+    ) -> anyhow::Result<Bitfield> {
+        let num_pieces = self.info.pieces.0.len();
+        let saved = match ResumeData::load(path) {
+            Ok(Some(saved))
+                if saved.info_hash == self.info_hash()
+                    && saved.pieces.len() == num_pieces
+                    && saved.files.len() == storage.paths().len() =>
+            {
+                saved
+            }
+            // No state, or state that belongs to something else: start from scratch.
+            _ => return Ok(Bitfield::new(num_pieces)),
+        };
 
-            file.write_all(&piece_buf).await?;
+        let current = ResumeData::capture(saved.info_hash, saved.pieces.clone(), storage)
+            .context("read file metadata")?;
+        let mut have = saved.pieces.clone();
+        for file_index in saved.changed_files(&current) {
+            for piece_index in self.info.file_pieces(file_index) {
+                have.set(piece_index, self.info.check_piece(storage, piece_index));
+            }
         }
-
-        Ok(())
+        Ok(have)
     }
 }
 
@@ -202,6 +313,112 @@ pub struct Info {
     // There is also a key length or a key files, but not both or neither.
     #[serde(flatten)]
     pub keys: Keys,
+
+    // (optional) If set to 1, clients must only get peers from the trackers listed in the torrent (BEP 27).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+}
+
+impl Info {
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Total number of bytes in the torrent, summed over all files.
+    pub fn length(&self) -> usize {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+
+    /// Length of the piece at `piece_index`; only the last piece may be shorter than
+    /// `piece_length`.
+    pub fn piece_len(&self, piece_index: usize) -> usize {
+        let start = piece_index * self.piece_length;
+        self.piece_length.min(self.length().saturating_sub(start))
+    }
+
+    /// Indexes of the pieces that hold data of the file at `file_index`.
+    pub fn file_pieces(&self, file_index: usize) -> Range<usize> {
+        let (offset, length) = match &self.keys {
+            Keys::SingleFile { length } => (0, *length),
+            Keys::MultiFile { files } => (
+                files[..file_index].iter().map(|f| f.length).sum(),
+                files[file_index].length,
+            ),
+        };
+        if length == 0 {
+            return 0..0;
+        }
+        offset / self.piece_length..(offset + length).div_ceil(self.piece_length)
+    }
+
+    /// Whether the piece at `piece_index` in `storage` matches its hash. Data that cannot be
+    /// read counts as a mismatch.
+    pub fn check_piece(&self, storage: &dyn Storage, piece_index: usize) -> bool {
+        match storage.read_block(piece_index, 0, self.piece_len(piece_index)) {
+            Ok(data) => Sha1::digest(&data)[..] == self.pieces.0[piece_index],
+            Err(_) => false,
+        }
+    }
+
+    /// The files of the torrent in the order their bytes appear in the pieces.
+    ///
+    /// Paths are relative and start with `name`; path components that could escape the
+    /// download directory are rejected.
+    pub fn files(&self) -> anyhow::Result<Vec<FileEntry>> {
+        check_path_component(&self.name)?;
+        match &self.keys {
+            Keys::SingleFile { length } => Ok(vec![FileEntry {
+                path: PathBuf::from(&self.name),
+                length: *length,
+                offset: 0,
+            }]),
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|f| {
+                        anyhow::ensure!(!f.path.is_empty(), "file with empty path");
+                        let mut path = PathBuf::from(&self.name);
+                        for component in &f.path {
+                            check_path_component(component)?;
+                            path.push(component);
+                        }
+                        let entry = FileEntry {
+                            path,
+                            length: f.length,
+                            offset,
+                        };
+                        offset += f.length;
+                        Ok(entry)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+fn check_path_component(component: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !component.is_empty()
+            && component != "."
+            && component != ".."
+            && !component.contains(['/', '\\']),
+        "invalid path component {:?}",
+        component
+    );
+    Ok(())
+}
+
+/// A file of the torrent, located within the concatenation of all files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: usize,
+    // Offset of the first byte of this file from the start of the first piece.
+    pub offset: usize,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -245,7 +462,7 @@ pub mod hashes {
         where
             E: serde::de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(serde::de::Error::invalid_length(v.len(), &self));
             }
 
@@ -276,48 +493,3 @@ pub mod hashes {
         }
     }
 }
-
-pub fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
-    match encoded_value.chars().next() {
-        Some('i') => {
-            let (_, rest) = encoded_value.split_once("i").unwrap();
-            let (n, rest) = rest.split_once("e").unwrap();
-            let n = n.parse::<i64>().unwrap();
-            return (n.into(), rest);
-        }
-        Some('l') => {
-            let (_, mut rest) = encoded_value.split_once("l").unwrap();
-            let mut list = Vec::new();
-            while !rest.is_empty() && !rest.starts_with("e") {
-                let (value, rem) = decode_bencoded_value(rest);
-                list.push(value);
-                rest = rem;
-            }
-            return (list.into(), &rest[1..]);
-        }
-        Some('d') => {
-            let (_, mut rest) = encoded_value.split_once("d").unwrap();
-            let mut dict = serde_json::Map::new();
-            while !rest.is_empty() && !rest.starts_with("e") {
-                let (k, rem) = decode_bencoded_value(rest);
-                let k = match k {
-                    serde_json::Value::String(s) => s,
-                    _ => panic!("Unexpected key type"),
-                };
-
-                let (v, rem) = decode_bencoded_value(rem);
-                dict.insert(k, v);
-                rest = rem;
-            }
-            return (dict.into(), &rest[1..]);
-        }
-        Some('0'..='9') => {
-            let (n, rest) = encoded_value.split_once(":").unwrap();
-            let n = n.parse::<usize>().unwrap();
-            return (serde_json::Value::String(rest[..n].to_string()), &rest[n..]);
-        }
-        _ => {
-            panic!("Unexpected end of encoded value")
-        }
-    }
-}
@@ -1,5 +1,24 @@
-use peers::Peers;
-use serde::{Deserialize, Serialize, Serializer};
+use anyhow::Context;
+use peers::{Peers, Peers6};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::{
+    bencode::{self, Value},
+    peer::PeerId,
+};
+
+pub mod list;
+pub mod session;
+pub mod udp;
+
+pub use list::TrackerList;
+pub use session::TrackerSession;
+pub use udp::UdpTracker;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct TrackerRequest {
@@ -10,8 +29,9 @@ pub struct TrackerRequest {
     // pub info_hash: String,
 
     // peer_id: a unique identifier for your client
-    // A string of length 20 that you get to pick. You can use something like 00112233445566778899.
-    pub peer_id: String,
+    // A string of length 20 that you get to pick, see `PeerId`. May hold any bytes, so it is
+    // URL encoded by hand like info_hash.
+    // pub peer_id: String,
 
     // port: the port your client is listening on
     // You can set this to 6881, you will not have to support this functionality during this challenge.
@@ -33,30 +53,148 @@ pub struct TrackerRequest {
     // For the purposes of this challenge, set this to 1.
     // The compact representation is more commonly used in the wild, the non-compact representation is mostly supported for backward-compatibility.
     pub compact: u8,
+
+    // ipv6/ipv4: our address in the other address family, so that a tracker we reach over one
+    // family can hand us out to peers of both (BEP 7).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Addr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv4: Option<Ipv4Addr>,
+
+    // event: started, completed or stopped; left out for regular announces
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<&'static str>,
+
+    // numwant: number of peers we would like to receive, the tracker picks if left out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<usize>,
+
+    // key: identifies us to the tracker across IP address changes; not shared with peers
+    pub key: String,
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct TrackerResponse {
+    // failure reason: if present, then no other keys may be present. The value is a
+    // human-readable error message as to why the request failed.
+    #[serde(rename = "failure reason", default)]
+    pub failure_reason: Option<String>,
+
+    // warning message: (new, optional) Similar to failure reason, but the response still gets
+    // processed normally.
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
+
     //     interval:
     // An integer, indicating how often your client should make a request to the tracker.
     // You can ignore this value for the purposes of this challenge.
     // peers.
-    pub interval: usize,
+    #[serde(default)]
+    pub interval: Option<usize>,
+
+    // min interval: (optional) Minimum announce interval. If present clients must not reannounce
+    // more frequently than this.
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<usize>,
+
+    // tracker id: A string that the client should send back on its next announcements.
+    #[serde(rename = "tracker id", default)]
+    pub tracker_id: Option<serde_bytes::ByteBuf>,
+
+    // complete: number of peers with the entire file, i.e. seeders (integer)
+    #[serde(default)]
+    pub complete: Option<usize>,
+
+    // incomplete: number of non-seeder peers, aka "leechers" (integer)
+    #[serde(default)]
+    pub incomplete: Option<usize>,
 
     // A string, which contains list of peers that your client can connect to.
     // Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+    // Trackers ignoring `compact` send a list of dictionaries with the keys `peer id`, `ip` and
+    // `port` instead.
+    #[serde(default)]
     pub peers: Peers,
+
+    // peers6: IPv6 peers in the compact model, 18 bytes each (BEP 7).
+    #[serde(default)]
+    pub peers6: Peers6,
+}
+
+/// Why a tracker request failed.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TrackerError {
+    // The tracker turned the request down.
+    #[error("tracker failure: {0}")]
+    Failure(String),
+    #[error("unsupported tracker URL {0}")]
+    UnsupportedUrl(String),
+    #[error("no trackers")]
+    NoTrackers,
+    #[error("tracker did not answer")]
+    Timeout,
+    // The tracker could not be reached, did not answer or sent something that is not a
+    // response.
+    #[error("{0}")]
+    Request(String),
 }
 
-mod peers {
-    use serde::{self, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+impl From<anyhow::Error> for TrackerError {
+    /// Keeps a `TrackerError` from further down as it is and turns anything else into a
+    /// [`TrackerError::Request`] with the whole chain of causes.
+    fn from(e: anyhow::Error) -> Self {
+        e.downcast()
+            .unwrap_or_else(|e| TrackerError::Request(format!("{:#}", e)))
+    }
+}
+
+pub(crate) mod peers {
+    use serde::{
+        self,
+        de::{SeqAccess, Visitor},
+        Deserialize, Deserializer,
+    };
     use std::{
         fmt,
-        net::{Ipv4Addr, SocketAddrV4},
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     };
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers(pub Vec<SocketAddr>);
+
+    // The `peers6` key (BEP 7): like compact `peers`, but every peer takes 18 bytes, the first 16
+    // bytes being the peer's IPv6 address and the last 2 bytes its port number.
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers6(pub Vec<SocketAddr>);
+
+    // A peer in the non-compact model. The peer id is not needed to connect, so it is skipped.
+    #[derive(Deserialize)]
+    struct DictPeer {
+        // ip: peer's IP address either IPv6 (hexed) or IPv4 (dotted quad) or DNS name (string)
+        ip: String,
+        // port: peer's port number (integer)
+        port: u16,
+    }
+
+    /// Splits a compact peer list into addresses of `width` bytes followed by a 2 byte port.
+    pub fn parse_compact(v: &[u8], width: usize) -> Option<Vec<SocketAddr>> {
+        if !v.len().is_multiple_of(width + 2) {
+            return None;
+        }
+        Some(
+            v.chunks_exact(width + 2)
+                .map(|x| {
+                    let ip = match width {
+                        4 => IpAddr::from(Ipv4Addr::new(x[0], x[1], x[2], x[3])),
+                        _ => IpAddr::from(Ipv6Addr::from(
+                            <[u8; 16]>::try_from(&x[..16]).expect("16 bytes"),
+                        )),
+                    };
+                    SocketAddr::new(ip, u16::from_be_bytes([x[width], x[width + 1]]))
+                })
+                .collect(),
+        )
+    }
 
     struct PeersVisitor;
 
@@ -71,21 +209,23 @@ mod peers {
         where
             E: serde::de::Error,
         {
-            if v.len() % 6 != 0 {
-                return Err(serde::de::Error::invalid_length(v.len(), &self));
-            }
+            parse_compact(v, 4)
+                .map(Peers)
+                .ok_or_else(|| serde::de::Error::invalid_length(v.len(), &self))
+        }
 
-            Ok(Peers(
-                v.chunks_exact(6)
-                    .into_iter()
-                    .map(|x| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(x[0], x[1], x[2], x[3]),
-                            u16::from_be_bytes([x[4], x[5]]),
-                        )
-                    })
-                    .collect(),
-            ))
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Vec::new();
+            while let Some(peer) = seq.next_element::<DictPeer>()? {
+                // Host names would need a DNS lookup, so only addresses are kept.
+                if let Ok(ip) = peer.ip.parse::<IpAddr>() {
+                    peers.push(SocketAddr::new(ip, peer.port));
+                }
+            }
+            Ok(Peers(peers))
         }
     }
 
@@ -94,9 +234,379 @@ mod peers {
         where
             D: Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(PeersVisitor)
+            deserializer.deserialize_any(PeersVisitor)
+        }
+    }
+
+    struct Peers6Visitor;
+
+    impl<'de> Visitor<'de> for Peers6Visitor {
+        type Value = Peers6;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("Each peer is represented using 18 bytes. The first 16 bytes are the peer's IPv6 address and the last 2 bytes are the peer's port number.")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            parse_compact(v, 16)
+                .map(Peers6)
+                .ok_or_else(|| serde::de::Error::invalid_length(v.len(), &self))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Peers6 {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(Peers6Visitor)
+        }
+    }
+}
+
+/// What we tell a tracker about ourselves and our download when announcing.
+#[derive(Debug, Clone)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    // Our publicly reachable addresses, if known.
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    pub event: Option<Event>,
+    // How many peers we want, or `None` for the tracker's default.
+    pub numwant: Option<usize>,
+    // Random value that stays the same for the whole session.
+    pub key: u32,
+}
+
+impl Announce {
+    /// A regular announce for a download with `left` bytes to go, with our public addresses
+    /// filled in and a fresh key.
+    pub fn new(info_hash: [u8; 20], peer_id: PeerId, left: usize) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            ipv4: public_ip(false).and_then(|ip| match ip {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            }),
+            ipv6: public_ip(true).and_then(|ip| match ip {
+                IpAddr::V6(ip) => Some(ip),
+                IpAddr::V4(_) => None,
+            }),
+            event: None,
+            numwant: None,
+            key: rand::random(),
+        }
+    }
+}
+
+/// Tells the tracker where in its lifecycle a download is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    // The first announce of a download.
+    Started,
+    // The download finished; not sent if it was already complete when it started.
+    Completed,
+    // We are shutting down gracefully.
+    Stopped,
+}
+
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceResponse {
+    // Number of seconds to wait before announcing again.
+    pub interval: usize,
+    // Never announce more often than this many seconds, if set.
+    pub min_interval: Option<usize>,
+    pub peers: Vec<SocketAddr>,
+    // Swarm counts, if the tracker reported them.
+    pub seeders: Option<usize>,
+    pub leechers: Option<usize>,
+    // A warning from the tracker; the response is valid nonetheless.
+    pub warning: Option<String>,
+    // To be sent back with the next announces, if set.
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+/// What a tracker knows about one torrent's swarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    // complete: number of peers with the entire file, i.e. seeders
+    pub complete: usize,
+    // incomplete: number of non-seeder peers, aka "leechers"
+    pub incomplete: usize,
+    // downloaded: total number of times the tracker registered a completion
+    pub downloaded: usize,
+}
+
+/// A tracker client, speaking HTTP or the UDP tracker protocol depending on the URL's scheme.
+#[derive(Debug)]
+pub enum TrackerClient {
+    Http(HttpTracker),
+    Udp(UdpTracker),
+}
+
+impl TrackerClient {
+    pub fn new(url: &str) -> crate::Result<Self> {
+        let tracker = match url.split_once("://").map(|(scheme, _)| scheme) {
+            Some("http" | "https") => HttpTracker::new(url).map(Self::Http),
+            Some("udp") => UdpTracker::new(url).map(Self::Udp),
+            _ => return Err(TrackerError::UnsupportedUrl(url.to_string()).into()),
+        };
+        Ok(tracker.map_err(TrackerError::from)?)
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            Self::Http(tracker) => tracker.url(),
+            Self::Udp(tracker) => tracker.url(),
+        }
+    }
+
+    pub async fn announce(&mut self, announce: &Announce) -> crate::Result<AnnounceResponse> {
+        let response = match self {
+            Self::Http(tracker) => tracker.announce(announce).await,
+            Self::Udp(tracker) => tracker.announce(announce).await,
+        };
+        Ok(response.map_err(TrackerError::from)?)
+    }
+
+    /// Asks the tracker for the swarm stats of every torrent in `info_hashes`, splitting them into
+    /// as few requests as the protocol allows. Torrents the tracker does not know are missing
+    /// from the result.
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> crate::Result<BTreeMap<[u8; 20], ScrapeStats>> {
+        let mut stats = BTreeMap::new();
+        match self {
+            Self::Http(tracker) => {
+                for batch in info_hashes.chunks(HTTP_MAX_SCRAPE) {
+                    stats.extend(tracker.scrape(batch).await.map_err(TrackerError::from)?);
+                }
+            }
+            Self::Udp(tracker) => {
+                for batch in info_hashes.chunks(udp::MAX_SCRAPE) {
+                    let batch_stats = tracker.scrape(batch).await.map_err(TrackerError::from)?;
+                    stats.extend(batch.iter().copied().zip(batch_stats));
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// How many info hashes go into one HTTP scrape, keeping the URL at a length servers accept.
+const HTTP_MAX_SCRAPE: usize = 50;
+
+/// How long an HTTP tracker gets to answer, so that a tracker that is down does not hold up
+/// trying the next one.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone)]
+pub struct HttpTracker {
+    url: String,
+    client: reqwest::Client,
+    // Handed out by the tracker, sent back with every following announce.
+    tracker_id: Option<Vec<u8>>,
+}
+
+impl HttpTracker {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .context("build HTTP client")?;
+        Ok(Self {
+            url: url.to_string(),
+            client,
+            tracker_id: None,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn announce(&mut self, announce: &Announce) -> anyhow::Result<AnnounceResponse> {
+        let request = TrackerRequest {
+            port: announce.port,
+            uploaded: announce.uploaded,
+            downloaded: announce.downloaded,
+            left: announce.left,
+            compact: 1,
+            ipv6: announce.ipv6,
+            ipv4: announce.ipv4,
+            event: announce.event.map(|e| e.as_str()),
+            numwant: announce.numwant,
+            key: format!("{:08x}", announce.key),
+        };
+
+        let url_params = serde_urlencoded::to_string(&request).context("Request to URL params")?;
+        // The announce URL may already carry a query string of its own.
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let mut tracker_url = format!(
+            "{}{}{}&info_hash={}&peer_id={}",
+            self.url,
+            separator,
+            url_params,
+            urlencode(&announce.info_hash).expect("encode info hash"),
+            urlencode(&announce.peer_id.0).expect("encode peer id")
+        );
+        if let Some(tracker_id) = &self.tracker_id {
+            tracker_url.push_str("&trackerid=");
+            tracker_url.push_str(&urlencoding::encode_binary(tracker_id));
+        }
+
+        let response = self.client.get(tracker_url).send().await?;
+        let response = response.bytes().await?;
+        let tracker_response: TrackerResponse =
+            serde_bencode::from_bytes(&response).context("deserialize response")?;
+        if let Some(reason) = tracker_response.failure_reason {
+            return Err(TrackerError::Failure(reason).into());
+        }
+        if let Some(warning) = &tracker_response.warning_message {
+            eprintln!("tracker {} warns: {}", self.url, warning);
+        }
+        if let Some(tracker_id) = &tracker_response.tracker_id {
+            self.tracker_id = Some(tracker_id.to_vec());
         }
+        Ok(AnnounceResponse {
+            interval: tracker_response
+                .interval
+                .context("tracker response has no interval")?,
+            min_interval: tracker_response.min_interval,
+            peers: tracker_response
+                .peers
+                .0
+                .into_iter()
+                .chain(tracker_response.peers6.0)
+                .collect(),
+            seeders: tracker_response.complete,
+            leechers: tracker_response.incomplete,
+            warning: tracker_response.warning_message,
+            tracker_id: tracker_response.tracker_id.map(|id| id.into_vec()),
+        })
     }
+
+    /// The scrape URL by convention: the last path component of the announce URL with
+    /// `announce` replaced by `scrape`. Trackers whose announce URL does not follow this
+    /// pattern do not support scraping.
+    pub fn scrape_url(&self) -> anyhow::Result<String> {
+        let (base, last) = self
+            .url
+            .rsplit_once('/')
+            .context("tracker URL has no path")?;
+        let rest = last
+            .strip_prefix("announce")
+            .context("tracker does not support scraping")?;
+        Ok(format!("{}/scrape{}", base, rest))
+    }
+
+    pub async fn scrape(
+        &self,
+        info_hashes: &[[u8; 20]],
+    ) -> anyhow::Result<BTreeMap<[u8; 20], ScrapeStats>> {
+        let scrape_url = self.scrape_url()?;
+        let mut url = scrape_url.clone();
+        for (i, info_hash) in info_hashes.iter().enumerate() {
+            // The announce URL may already carry a query string of its own.
+            let separator = if i == 0 && !scrape_url.contains('?') {
+                '?'
+            } else {
+                '&'
+            };
+            url.push(separator);
+            url.push_str("info_hash=");
+            url.push_str(&urlencode(info_hash)?);
+        }
+
+        let response = self.client.get(url).send().await?;
+        let response = response.bytes().await?;
+        let response = bencode::decode(&response).context("decode scrape response")?;
+        if let Some(reason) = response.get("failure reason").and_then(Value::as_str) {
+            return Err(TrackerError::Failure(reason.to_string()).into());
+        }
+        let files = response
+            .get("files")
+            .and_then(Value::as_dict)
+            .context("scrape response has no files")?;
+
+        let field = |stats: &Value, key| {
+            stats
+                .get(key)
+                .and_then(Value::as_int)
+                .and_then(|n| usize::try_from(n).ok())
+                .unwrap_or(0)
+        };
+        Ok(files
+            .iter()
+            .filter_map(|(info_hash, stats)| {
+                let info_hash = <[u8; 20]>::try_from(info_hash.as_slice()).ok()?;
+                let stats = ScrapeStats {
+                    complete: field(stats, "complete"),
+                    incomplete: field(stats, "incomplete"),
+                    downloaded: field(stats, "downloaded"),
+                };
+                Some((info_hash, stats))
+            })
+            .collect())
+    }
+}
+
+/// Announces to the first tracker of `trackers` that answers and returns the peers it knows for
+/// `info_hash`.
+pub async fn get_peers(
+    trackers: &mut TrackerList,
+    info_hash: &[u8; 20],
+    peer_id: PeerId,
+    left: usize,
+) -> crate::Result<Vec<SocketAddr>> {
+    let announce = Announce::new(*info_hash, peer_id, left);
+    Ok(trackers.announce(&announce).await?.peers)
+}
+
+/// Finds the address this host uses to reach the internet over IPv6 (or IPv4), if it is
+/// publicly routable. Connecting a UDP socket sends nothing, it only makes the OS pick a route.
+pub fn public_ip(v6: bool) -> Option<IpAddr> {
+    let (local, remote) = if v6 {
+        ("[::]:0", "[2001:4860:4860::8888]:53")
+    } else {
+        ("0.0.0.0:0", "8.8.8.8:53")
+    };
+    let socket = UdpSocket::bind(local).ok()?;
+    socket.connect(remote).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    let public = match ip {
+        IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local()),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // Neither loopback, link-local (fe80::/10) nor unique local (fc00::/7).
+            !(ip.is_loopback() || first & 0xffc0 == 0xfe80 || first & 0xfe00 == 0xfc00)
+        }
+    };
+    (public && !ip.is_unspecified()).then_some(ip)
 }
 
 pub fn urlencode(t: &[u8; 20]) -> anyhow::Result<String> {
@@ -107,3 +617,134 @@ pub fn urlencode(t: &[u8; 20]) -> anyhow::Result<String> {
     }
     Ok(s)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_tracker_errors_behind_context() {
+        let e = anyhow::Error::from(TrackerError::Failure("not registered".to_string()))
+            .context("announce");
+        assert_eq!(
+            TrackerError::from(e),
+            TrackerError::Failure("not registered".to_string())
+        );
+        let e = anyhow::anyhow!("connection refused").context("send to tracker");
+        assert_eq!(
+            TrackerError::from(e),
+            TrackerError::Request("send to tracker: connection refused".to_string())
+        );
+    }
+
+    fn response(bytes: &[u8]) -> TrackerResponse {
+        serde_bencode::from_bytes(bytes).unwrap()
+    }
+
+    #[test]
+    fn decodes_failure_only_responses() {
+        let response = response(b"d14:failure reason22:torrent not registerede");
+        assert_eq!(
+            response.failure_reason.as_deref(),
+            Some("torrent not registered")
+        );
+        assert_eq!(response.interval, None);
+        assert!(response.peers.0.is_empty());
+    }
+
+    #[test]
+    fn decodes_every_key_of_a_full_response() {
+        let response = response(
+            &[
+                &b"d8:completei3e10:incompletei1e8:intervali1800e12:min intervali60e5:peers12:"[..],
+                &[127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2],
+                b"10:tracker id3:abc15:warning message7:be nicee",
+            ]
+            .concat(),
+        );
+        assert_eq!(response.failure_reason, None);
+        assert_eq!(response.warning_message.as_deref(), Some("be nice"));
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!(response.min_interval, Some(60));
+        assert_eq!(
+            response.tracker_id.map(|id| id.into_vec()),
+            Some(b"abc".to_vec())
+        );
+        assert_eq!(response.complete, Some(3));
+        assert_eq!(response.incomplete, Some(1));
+        assert_eq!(
+            response.peers.0,
+            [
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_dictionary_model_peers() {
+        let response = response(
+            b"d8:intervali60e5:peersl\
+              d2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee\
+              d2:ip3:::14:porti6882ee\
+              d2:ip11:example.com4:porti1ee\
+              ee",
+        );
+        // The host name would need a lookup and is left out.
+        assert_eq!(
+            response.peers.0,
+            [
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_partial_compact_peers() {
+        let result =
+            serde_bencode::from_bytes::<TrackerResponse>(b"d8:intervali60e5:peers5:abcdee");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn decodes_ipv6_peers() {
+        let mut peer6 = [0u8; 18];
+        peer6[15] = 1;
+        peer6[16..].copy_from_slice(&6883u16.to_be_bytes());
+        let dual_stack = response(
+            &[
+                &b"d8:intervali60e5:peers6:"[..],
+                &[127, 0, 0, 1, 0x1a, 0xe1],
+                b"6:peers618:",
+                &peer6,
+                b"e",
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            dual_stack.peers.0,
+            ["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            dual_stack.peers6.0,
+            ["[::1]:6883".parse::<SocketAddr>().unwrap()]
+        );
+        // Without `peers6`, there are no IPv6 peers.
+        assert!(response(b"d8:intervali60e5:peers0:e").peers6.0.is_empty());
+    }
+
+    #[test]
+    fn rejects_partial_ipv6_peers() {
+        let bytes = [&b"d8:intervali60e6:peers617:"[..], &[0; 17], b"e"].concat();
+        assert!(serde_bencode::from_bytes::<TrackerResponse>(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_urls() {
+        assert!(matches!(
+            TrackerClient::new("ftp://example.com/announce"),
+            Err(crate::Error::Tracker(TrackerError::UnsupportedUrl(_)))
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+//! Multitracker metadata (BEP 12).
+//!
+//! `announce-list` holds tiers of trackers. Trackers are tried one tier after the other, and
+//! within a tier in an order that is shuffled once when the list is loaded. A tracker that answers
+//! moves to the front of its tier, so it is tried first on the next announce.
+use rand::seq::SliceRandom;
+use std::time::Duration;
+
+use super::{Announce, AnnounceResponse, TrackerClient, TrackerError};
+
+/// With other trackers to fall back on, a UDP tracker gets a much shorter back-off than the
+/// BEP 15 one, which keeps retrying for about an hour: `UDP_TIMEOUT * 2 ^ n` for up to
+/// `UDP_RETRIES` retransmissions. A lone tracker keeps the BEP 15 back-off.
+const UDP_TIMEOUT: Duration = Duration::from_secs(3);
+const UDP_RETRIES: u32 = 2;
+
+#[derive(Debug)]
+pub struct TrackerList {
+    tiers: Vec<Vec<TrackerClient>>,
+    // Tier of the tracker that answered last; it sits at the front of that tier.
+    working_tier: Option<usize>,
+}
+
+impl TrackerList {
+    /// Builds the list from tiers of tracker URLs. Trackers with an unsupported URL scheme are
+    /// left out, and so are the tiers left empty.
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        let mut tiers: Vec<Vec<TrackerClient>> = tiers
+            .into_iter()
+            .map(|tier| {
+                let mut tier: Vec<TrackerClient> = tier
+                    .iter()
+                    .filter_map(|url| match TrackerClient::new(url) {
+                        Ok(tracker) => Some(tracker),
+                        Err(e) => {
+                            eprintln!("skipping tracker: {:#}", e);
+                            None
+                        }
+                    })
+                    .collect();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        if tiers.iter().map(Vec::len).sum::<usize>() > 1 {
+            tiers = tiers
+                .into_iter()
+                .map(|tier| tier.into_iter().map(fail_fast).collect())
+                .collect();
+        }
+        Self {
+            tiers,
+            working_tier: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// The trackers tier by tier, in the order they will be tried next.
+    pub fn tiers(&self) -> impl Iterator<Item = &[TrackerClient]> {
+        self.tiers.iter().map(Vec::as_slice)
+    }
+
+    /// The tracker that answered the last announce, if any did.
+    pub fn working(&mut self) -> Option<&mut TrackerClient> {
+        self.tiers.get_mut(self.working_tier?)?.first_mut()
+    }
+
+    /// Announces to the first tracker that answers, promoting it to the front of its tier.
+    /// Fails with the error of the last tracker tried if none answers.
+    pub async fn announce(&mut self, announce: &Announce) -> crate::Result<AnnounceResponse> {
+        let mut last_error = None;
+        for (tier_index, tier) in self.tiers.iter_mut().enumerate() {
+            for i in 0..tier.len() {
+                match tier[i].announce(announce).await {
+                    Ok(response) => {
+                        let tracker = tier.remove(i);
+                        tier.insert(0, tracker);
+                        self.working_tier = Some(tier_index);
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        eprintln!("tracker {} failed: {:#}", tier[i].url(), e);
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| TrackerError::NoTrackers.into()))
+    }
+}
+
+/// Gives a UDP tracker the short back-off, so that announcing moves on to the next tracker soon.
+fn fail_fast(tracker: TrackerClient) -> TrackerClient {
+    match tracker {
+        TrackerClient::Udp(tracker) => {
+            TrackerClient::Udp(tracker.with_backoff(UDP_TIMEOUT, UDP_RETRIES))
+        }
+        tracker => tracker,
+    }
+}
@@ -0,0 +1,144 @@
+//! The announces of a single download, from `started` to `stopped`.
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::{Announce, AnnounceResponse, Event, TrackerList};
+use crate::peer::PeerId;
+
+/// How long to wait between announces until a tracker tells us.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How long to wait before asking for more peers if the tracker sets no `min interval`, and
+/// before retrying a failed announce.
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many peers to ask for in every announce.
+const NUMWANT: usize = 50;
+
+/// How long shutting down waits for the tracker to acknowledge `stopped`.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps the trackers of a download informed: `started` first, regular announces every
+/// `interval` seconds with up to date transfer counters, `completed` once the last piece is
+/// verified and `stopped` on shutdown.
+#[derive(Debug)]
+pub struct TrackerSession {
+    trackers: TrackerList,
+    announce: Announce,
+    // When the last announce was sent.
+    last_announce: Option<Instant>,
+    // When the next regular announce is due.
+    next_announce: Instant,
+    interval: Duration,
+    min_interval: Option<Duration>,
+}
+
+impl TrackerSession {
+    pub fn new(trackers: TrackerList, info_hash: [u8; 20], peer_id: PeerId, left: usize) -> Self {
+        let mut announce = Announce::new(info_hash, peer_id, left);
+        announce.numwant = Some(NUMWANT);
+        Self {
+            trackers,
+            announce,
+            last_announce: None,
+            next_announce: Instant::now(),
+            interval: DEFAULT_INTERVAL,
+            min_interval: None,
+        }
+    }
+
+    pub fn add_uploaded(&mut self, bytes: usize) {
+        self.announce.uploaded += bytes;
+    }
+
+    pub fn add_downloaded(&mut self, bytes: usize) {
+        self.announce.downloaded += bytes;
+    }
+
+    pub fn set_left(&mut self, left: usize) {
+        self.announce.left = left;
+    }
+
+    /// Sends `started` and returns the peers the tracker handed out.
+    pub async fn start(&mut self) -> anyhow::Result<Vec<SocketAddr>> {
+        Ok(self.send(Some(Event::Started)).await?.peers)
+    }
+
+    /// When the tracker expects the next regular announce.
+    pub fn next_announce(&self) -> Instant {
+        self.next_announce
+    }
+
+    /// Sends a regular announce if `interval` has passed since the last one, returning the new
+    /// peers the tracker handed out.
+    pub async fn announce_if_due(&mut self) -> anyhow::Result<Option<Vec<SocketAddr>>> {
+        if Instant::now() < self.next_announce() {
+            return Ok(None);
+        }
+        Ok(Some(self.send(None).await?.peers))
+    }
+
+    /// Whether the tracker allows asking for more peers ahead of the regular interval.
+    pub fn can_request_peers(&self) -> bool {
+        Instant::now() >= self.next_peer_request()
+    }
+
+    /// From when on the tracker allows asking for more peers, see [`Self::request_peers`].
+    pub fn next_peer_request(&self) -> Instant {
+        match self.last_announce {
+            Some(last) => last + self.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL),
+            None => Instant::now(),
+        }
+    }
+
+    /// Announces ahead of the regular interval to get more peers, respecting `min interval`.
+    pub async fn request_peers(&mut self) -> anyhow::Result<Vec<SocketAddr>> {
+        anyhow::ensure!(
+            self.can_request_peers(),
+            "tracker asks not to announce more often than every {:?}",
+            self.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL)
+        );
+        Ok(self.send(None).await?.peers)
+    }
+
+    /// Tells the tracker the download finished.
+    pub async fn completed(&mut self) -> anyhow::Result<()> {
+        self.announce.left = 0;
+        self.send(Some(Event::Completed)).await.map(|_| ())
+    }
+
+    /// Tells the tracker we are leaving the swarm. Only the tracker that answered before is
+    /// told, and it gets [`STOP_TIMEOUT`] to answer, so shutting down never waits long on a
+    /// tracker that went down.
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        let mut announce = self.announce.clone();
+        announce.event = Some(Event::Stopped);
+        announce.numwant = Some(0);
+        if let Some(tracker) = self.trackers.working() {
+            tokio::time::timeout(STOP_TIMEOUT, tracker.announce(&announce))
+                .await
+                .map_err(|_| anyhow::anyhow!("tracker did not answer stopped in time"))??;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, event: Option<Event>) -> anyhow::Result<AnnounceResponse> {
+        self.announce.event = event;
+        self.last_announce = Some(Instant::now());
+        let response = match self.trackers.announce(&self.announce).await {
+            Ok(response) => response,
+            Err(e) => {
+                self.next_announce = Instant::now() + DEFAULT_MIN_INTERVAL;
+                return Err(e.into());
+            }
+        };
+        self.interval = Duration::from_secs(response.interval as u64);
+        self.next_announce = Instant::now() + self.interval;
+        self.min_interval = response
+            .min_interval
+            .map(|secs| Duration::from_secs(secs as u64));
+        Ok(response)
+    }
+}
@@ -0,0 +1,382 @@
+//! The UDP tracker protocol (BEP 15).
+//!
+//! Every exchange is a single datagram each way. Before announcing or scraping, a client obtains a
+//! connection id from the tracker, which it may keep using for one minute. Requests that go
+//! unanswered are retransmitted after 15 * 2 ^ n seconds, where n starts at 0 and is incremented
+//! after every timeout up to 8.
+use anyhow::Context;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+
+use super::{peers::parse_compact, Announce, AnnounceResponse, Event, ScrapeStats, TrackerError};
+
+// Magic constant identifying a connect request.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const CONNECT: u32 = 0;
+const ANNOUNCE: u32 = 1;
+const SCRAPE: u32 = 2;
+const ERROR: u32 = 3;
+
+/// How long a connection id may be used after it was handed out.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+/// Trackers may limit a scrape to this many info hashes, so that the request fits a datagram.
+pub const MAX_SCRAPE: usize = 74;
+
+#[derive(Debug)]
+pub struct UdpTracker {
+    url: String,
+    // The tracker's address, once resolved.
+    addr: Option<SocketAddr>,
+    // The connection id we hold and when we received it.
+    connection: Option<(u64, Instant)>,
+    // Timeout before the first retransmission; doubled after every timeout.
+    retransmit_timeout: Duration,
+    max_retries: u32,
+}
+
+impl UdpTracker {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(url.starts_with("udp://"), "not a udp:// tracker URL");
+        Ok(Self {
+            url: url.to_string(),
+            addr: None,
+            connection: None,
+            retransmit_timeout: Duration::from_secs(15),
+            max_retries: 8,
+        })
+    }
+
+    /// Overrides the BEP 15 back-off, waiting `timeout * 2 ^ n` for the nth retransmission and
+    /// giving up after `max_retries` of them.
+    pub fn with_backoff(mut self, timeout: Duration, max_retries: u32) -> Self {
+        self.retransmit_timeout = timeout;
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn announce(&mut self, announce: &Announce) -> anyhow::Result<AnnounceResponse> {
+        let response = self
+            .request(ANNOUNCE, |buf| {
+                buf.extend_from_slice(&announce.info_hash);
+                buf.extend_from_slice(&announce.peer_id.0);
+                buf.extend_from_slice(&(announce.downloaded as u64).to_be_bytes());
+                buf.extend_from_slice(&(announce.left as u64).to_be_bytes());
+                buf.extend_from_slice(&(announce.uploaded as u64).to_be_bytes());
+                // event: 0 none, 1 completed, 2 started, 3 stopped
+                let event: u32 = match announce.event {
+                    None => 0,
+                    Some(Event::Completed) => 1,
+                    Some(Event::Started) => 2,
+                    Some(Event::Stopped) => 3,
+                };
+                buf.extend_from_slice(&event.to_be_bytes());
+                // IP address: the one the request came from
+                buf.extend_from_slice(&0u32.to_be_bytes());
+                buf.extend_from_slice(&announce.key.to_be_bytes());
+                // num_want: -1 for the tracker's default
+                let num_want = announce
+                    .numwant
+                    .map_or(-1, |n| i32::try_from(n).unwrap_or(i32::MAX));
+                buf.extend_from_slice(&num_want.to_be_bytes());
+                buf.extend_from_slice(&announce.port.to_be_bytes());
+            })
+            .await?;
+
+        anyhow::ensure!(response.len() >= 12, "announce response is too short");
+        // Trackers answer announces over IPv6 with IPv6 peers (BEP 15).
+        let width = match self.addr {
+            Some(SocketAddr::V6(_)) => 16,
+            _ => 4,
+        };
+        let peers = parse_compact(&response[12..], width)
+            .context("announce response has a partial peer")?;
+        Ok(AnnounceResponse {
+            interval: read_u32(&response, 0) as usize,
+            min_interval: None,
+            leechers: Some(read_u32(&response, 4) as usize),
+            seeders: Some(read_u32(&response, 8) as usize),
+            warning: None,
+            tracker_id: None,
+            peers,
+        })
+    }
+
+    /// Scrapes up to [`MAX_SCRAPE`] torrents at once, returning their stats in the same order.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+        anyhow::ensure!(
+            info_hashes.len() <= MAX_SCRAPE,
+            "cannot scrape more than {} torrents at once",
+            MAX_SCRAPE
+        );
+        let response = self
+            .request(SCRAPE, |buf| {
+                for info_hash in info_hashes {
+                    buf.extend_from_slice(info_hash);
+                }
+            })
+            .await?;
+
+        anyhow::ensure!(
+            response.len() >= info_hashes.len() * 12,
+            "scrape response is too short"
+        );
+        Ok(response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|x| ScrapeStats {
+                complete: read_u32(x, 0) as usize,
+                downloaded: read_u32(x, 4) as usize,
+                incomplete: read_u32(x, 8) as usize,
+            })
+            .collect())
+    }
+
+    /// Sends a request with the given action, whose body after the header is written by
+    /// `body`, and returns the body of the response. Obtains a connection id first if needed and
+    /// retransmits with exponential back-off.
+    async fn request(
+        &mut self,
+        action: u32,
+        body: impl Fn(&mut Vec<u8>),
+    ) -> anyhow::Result<Vec<u8>> {
+        let socket = self.connect_socket().await?;
+        for n in 0..=self.max_retries {
+            let timeout = self.retransmit_timeout * 2u32.pow(n);
+
+            let connection_id = match self.connection {
+                Some((id, since)) if since.elapsed() < CONNECTION_ID_TTL => id,
+                _ => {
+                    let response =
+                        match exchange(&socket, CONNECT, PROTOCOL_ID, timeout, |_| {}).await? {
+                            Some(response) => response,
+                            None => continue,
+                        };
+                    anyhow::ensure!(response.len() >= 8, "connect response is too short");
+                    let id = u64::from_be_bytes(response[..8].try_into().expect("8 bytes"));
+                    self.connection = Some((id, Instant::now()));
+                    id
+                }
+            };
+
+            if let Some(response) = exchange(&socket, action, connection_id, timeout, &body).await?
+            {
+                return Ok(response);
+            }
+        }
+        Err(TrackerError::Timeout.into())
+    }
+
+    async fn connect_socket(&mut self) -> anyhow::Result<UdpSocket> {
+        let addr = match self.addr {
+            Some(addr) => addr,
+            None => {
+                let addr = self.resolve().await?;
+                self.addr = Some(addr);
+                addr
+            }
+        };
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().expect("valid address"),
+            SocketAddr::V6(_) => "[::]:0".parse().expect("valid address"),
+        };
+        let socket = UdpSocket::bind(local).await.context("bind UDP socket")?;
+        socket.connect(addr).await.context("connect UDP socket")?;
+        Ok(socket)
+    }
+
+    async fn resolve(&self) -> anyhow::Result<SocketAddr> {
+        let host = self
+            .url
+            .strip_prefix("udp://")
+            .expect("checked in new")
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        tokio::net::lookup_host(host)
+            .await
+            .with_context(|| format!("resolve {}", host))?
+            .next()
+            .with_context(|| format!("{} has no address", host))
+    }
+}
+
+/// Sends one request and waits up to `timeout` for the response with the same transaction id.
+/// Returns the response's body, or `None` if the tracker did not answer in time.
+async fn exchange(
+    socket: &UdpSocket,
+    action: u32,
+    connection_id: u64,
+    timeout: Duration,
+    body: impl Fn(&mut Vec<u8>),
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let transaction_id: u32 = rand::random();
+    let mut request = Vec::with_capacity(98);
+    request.extend_from_slice(&connection_id.to_be_bytes());
+    request.extend_from_slice(&action.to_be_bytes());
+    request.extend_from_slice(&transaction_id.to_be_bytes());
+    body(&mut request);
+    socket.send(&request).await.context("send to tracker")?;
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let len = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(len) => len.context("receive from tracker")?,
+            Err(_) => return Ok(None),
+        };
+        let response = &buf[..len];
+        // Datagrams that are too short or answer another request are dropped.
+        if len < 8 || read_u32(response, 4) != transaction_id {
+            continue;
+        }
+        match read_u32(response, 0) {
+            ERROR => {
+                let reason = String::from_utf8_lossy(&response[8..]).into_owned();
+                return Err(TrackerError::Failure(reason).into());
+            }
+            a if a == action => return Ok(Some(response[8..].to_vec())),
+            a => anyhow::bail!("tracker answered with action {}, expected {}", a, action),
+        }
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().expect("4 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerId;
+
+    /// A tracker stand-in and a client pointed at it.
+    async fn setup() -> (UdpSocket, UdpTracker) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        (socket, UdpTracker::new(&url).unwrap())
+    }
+
+    /// Receives a request, returning its connection id, action and transaction id.
+    async fn recv_request(socket: &UdpSocket) -> (u64, u32, u32, SocketAddr) {
+        let mut buf = vec![0u8; 1024];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert!(len >= 16, "request of {} bytes", len);
+        let connection_id = u64::from_be_bytes(buf[..8].try_into().unwrap());
+        (connection_id, read_u32(&buf, 8), read_u32(&buf, 12), from)
+    }
+
+    async fn respond(socket: &UdpSocket, to: SocketAddr, action: u32, tid: u32, body: &[u8]) {
+        let mut response = Vec::new();
+        response.extend_from_slice(&action.to_be_bytes());
+        response.extend_from_slice(&tid.to_be_bytes());
+        response.extend_from_slice(body);
+        socket.send_to(&response, to).await.unwrap();
+    }
+
+    /// Answers a connect request with `connection_id`.
+    async fn answer_connect(socket: &UdpSocket, connection_id: u64) {
+        let (id, action, tid, from) = recv_request(socket).await;
+        assert_eq!((id, action), (PROTOCOL_ID, CONNECT));
+        respond(socket, from, CONNECT, tid, &connection_id.to_be_bytes()).await;
+    }
+
+    /// Answers an announce request, which must carry `connection_id`, with one peer.
+    async fn answer_announce(socket: &UdpSocket, connection_id: u64) {
+        let (id, action, tid, from) = recv_request(socket).await;
+        assert_eq!((id, action), (connection_id, ANNOUNCE));
+        let mut body = Vec::new();
+        for n in [1800u32, 3, 5] {
+            body.extend_from_slice(&n.to_be_bytes());
+        }
+        body.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        respond(socket, from, ANNOUNCE, tid, &body).await;
+    }
+
+    fn announce() -> Announce {
+        Announce::new([7; 20], PeerId([1; 20]), 100)
+    }
+
+    #[tokio::test]
+    async fn connects_then_announces_and_reuses_the_connection_id() {
+        let (socket, mut tracker) = setup().await;
+        let server = tokio::spawn(async move {
+            answer_connect(&socket, 42).await;
+            answer_announce(&socket, 42).await;
+            // Within a minute, the second announce goes out without connecting again.
+            answer_announce(&socket, 42).await;
+        });
+
+        let response = tracker.announce(&announce()).await.unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.leechers, Some(3));
+        assert_eq!(response.seeders, Some(5));
+        assert_eq!(response.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        tracker.announce(&announce()).await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_responses_to_other_transactions() {
+        let (socket, mut tracker) = setup().await;
+        let server = tokio::spawn(async move {
+            let (_, _, tid, from) = recv_request(&socket).await;
+            let other = tid.wrapping_add(1);
+            respond(&socket, from, CONNECT, other, &13u64.to_be_bytes()).await;
+            respond(&socket, from, CONNECT, tid, &42u64.to_be_bytes()).await;
+            answer_announce(&socket, 42).await;
+        });
+
+        tracker.announce(&announce()).await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_errors() {
+        let (socket, mut tracker) = setup().await;
+        let server = tokio::spawn(async move {
+            let (_, _, tid, from) = recv_request(&socket).await;
+            respond(&socket, from, ERROR, tid, b"torrent not registered").await;
+        });
+
+        let error = tracker.announce(&announce()).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<TrackerError>(),
+            Some(&TrackerError::Failure("torrent not registered".to_string()))
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retransmits_when_a_reply_is_lost() {
+        let (socket, tracker) = setup().await;
+        let mut tracker = tracker.with_backoff(Duration::from_millis(50), 2);
+        let server = tokio::spawn(async move {
+            // The first connect request goes unanswered.
+            recv_request(&socket).await;
+            answer_connect(&socket, 42).await;
+            answer_announce(&socket, 42).await;
+        });
+
+        tracker.announce(&announce()).await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_retransmission() {
+        let (socket, tracker) = setup().await;
+        let mut tracker = tracker.with_backoff(Duration::from_millis(10), 1);
+
+        let error = tracker.announce(&announce()).await.unwrap_err();
+        assert_eq!(error.to_string(), "tracker did not answer");
+        drop(socket);
+    }
+}
@@ -0,0 +1,82 @@
+//! Checking data on disk against the piece hashes of a torrent.
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::{bitfield::Bitfield, storage::Storage, torrent::Info};
+
+/// Outcome of checking every piece of a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    // Whether each piece matched its hash.
+    pub pieces: Bitfield,
+}
+
+impl Verification {
+    /// Number of pieces that matched their hash.
+    pub fn completed(&self) -> usize {
+        self.pieces.count()
+    }
+
+    /// Percentage of pieces that matched their hash.
+    pub fn percent(&self) -> f64 {
+        if self.pieces.is_empty() {
+            return 100.0;
+        }
+        self.completed() as f64 * 100.0 / self.pieces.len() as f64
+    }
+
+    /// Indexes of the pieces that are missing or corrupt.
+    pub fn bad_pieces(&self) -> Vec<usize> {
+        (0..self.pieces.len())
+            .filter(|&i| !self.pieces.get(i))
+            .collect()
+    }
+}
+
+/// Hashes every piece in `storage` and compares it with the torrent's piece hashes, using one
+/// thread per CPU core.
+pub fn verify(info: &Info, storage: &dyn Storage) -> Verification {
+    Verification {
+        pieces: map_pieces(info.pieces.0.len(), |piece_index| {
+            info.check_piece(storage, piece_index)
+        })
+        .into_iter()
+        .collect(),
+    }
+}
+
+/// Runs `f` for every piece index in `0..num_pieces` on all CPU cores and collects the results
+/// in piece order.
+pub(crate) fn map_pieces<T, F>(num_pieces: usize, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync,
+{
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(num_pieces.max(1));
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(num_pieces));
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| loop {
+                let piece_index = next.fetch_add(1, Ordering::Relaxed);
+                if piece_index >= num_pieces {
+                    break;
+                }
+                let result = f(piece_index);
+                results.lock().unwrap().push((piece_index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_unstable_by_key(|(piece_index, _)| *piece_index);
+    results.into_iter().map(|(_, result)| result).collect()
+}